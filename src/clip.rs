@@ -0,0 +1,601 @@
+use anyhow::Result;
+use log::{debug, warn};
+use quick_xml::events::BytesStart;
+use svgtypes::Transform;
+
+use crate::{
+    SvgStyle, apply_transform,
+    defs::{Definitions, property},
+    emit::Emitter,
+    geometry::{Polylines, Segment, Subpath},
+    transform_multiply,
+};
+
+/// Samples per curve when searching for crossings with clip edges
+const CROSSING_STEPS: usize = 32;
+
+/// The area of a `clipPath` in output coordinates, the union of its shapes
+#[derive(Debug, Clone)]
+pub struct Region {
+    shapes: Vec<Polylines>,
+}
+
+impl Region {
+    /// Resolves the `clip-path` of an element, whose user space maps to the output
+    /// through `transform`. `bbox` is the element's bounding box in user space.
+    pub fn from_element(
+        element: &BytesStart,
+        transform: &Transform,
+        bbox: Option<[f64; 4]>,
+        defs: &Definitions,
+    ) -> Result<Option<Region>> {
+        let Some(href) = property(element, "clip-path")?.filter(|h| h != "none") else {
+            return Ok(None);
+        };
+        let Some(def) = defs.clip_path(&href) else {
+            warn!("Unknown clip path {}", href);
+            return Ok(None);
+        };
+        let mut transform = *transform;
+        if def.bounding_box {
+            let Some([x, y, w, h]) = bbox else {
+                warn!(
+                    "Clip path {} in bounding box units on a group, ignored",
+                    href
+                );
+                return Ok(None);
+            };
+            transform = transform_multiply(&transform, &Transform::new(w, 0.0, 0.0, h, x, y));
+        }
+        let transform = transform_multiply(&transform, &def.transform);
+        let shapes: Vec<Polylines> = def
+            .shapes
+            .iter()
+            .map(|shape| {
+                Polylines(
+                    shape
+                        .0
+                        .iter()
+                        .map(|line| {
+                            line.iter()
+                                .map(|p| apply_transform(*p, &transform))
+                                .collect()
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        debug!("clip path {} with {} shapes", href, shapes.len());
+        Ok(Some(Region { shapes }))
+    }
+
+    fn contains(&self, p: (f64, f64)) -> bool {
+        // even-odd within a shape, union of the shapes
+        self.shapes
+            .iter()
+            .any(|shape| shape.0.iter().filter(|line| winds_around(line, p)).count() % 2 == 1)
+    }
+
+    // every polyline is closed, as clip shapes are filled
+    fn edges(&self) -> impl Iterator<Item = ((f64, f64), (f64, f64))> {
+        self.shapes
+            .iter()
+            .flat_map(|shape| shape.0.iter())
+            .flat_map(|line| (0..line.len()).map(move |i| (line[i], line[(i + 1) % line.len()])))
+    }
+
+    /// Convex polygons covering the clip to cut fills with, if its shapes are
+    /// simple polygons
+    fn convex_parts(&self) -> Option<Vec<Vec<(f64, f64)>>> {
+        let mut parts = Vec::new();
+        for shape in &self.shapes {
+            match shape.0.as_slice() {
+                [polygon] if is_convex(polygon) => parts.push(polygon.clone()),
+                [polygon] => parts.extend(convex_pieces(polygon)?),
+                _ => return None,
+            }
+        }
+        Some(parts)
+    }
+
+    /// Bounds of the clip as the lower left and upper right corner
+    pub fn bounds(&self) -> Option<[f64; 4]> {
+        let lines = Polylines(self.shapes.iter().flat_map(|s| s.0.clone()).collect());
+        let [x, y, w, h] = lines.bounds()?;
+        Some([x, y, x + w, y + h])
+    }
+
+    /// Whether the clip is a rectangle along the axes, which its bounds match
+    pub fn is_rectangle(&self) -> bool {
+        let [shape] = self.shapes.as_slice() else {
+            return false;
+        };
+        let [polygon] = shape.0.as_slice() else {
+            return false;
+        };
+        let Some([x0, y0, x1, y1]) = self.bounds() else {
+            return false;
+        };
+        let on_edge = |v: f64, a: f64, b: f64| (v - a).abs() < 1e-9 || (v - b).abs() < 1e-9;
+        polygon
+            .iter()
+            .all(|p| on_edge(p.0, x0, x1) && on_edge(p.1, y0, y1))
+    }
+}
+
+/// Whether clipping leaves the outline as it is
+pub fn covers(regions: &[Region], outline: &[Subpath]) -> bool {
+    let segments = || outline.iter().flat_map(|s| s.segments.iter());
+    segments().all(|s| visible_parts(s, regions) == [(0.0, 1.0)])
+        // clips without any crossing may still lie within the shape
+        && regions.iter().all(|region| {
+            region
+                .shapes
+                .iter()
+                .flat_map(|s| s.0.iter().flatten())
+                .all(|p| !outline.iter().any(|s| winds_around(&s.polygon(), *p)))
+        })
+}
+
+/// Writes the visible parts of an outline: fills cut to the clip as polygons and
+/// strokes as the pieces of their segments inside it.
+pub fn emit_clipped(
+    outline: &[Subpath],
+    style: Option<&SvgStyle>,
+    regions: &[Region],
+    emitter: &dyn Emitter,
+) {
+    if let Some(style) = style
+        && style.fill.as_ref().is_some_and(|f| f != "none")
+    {
+        let (convex, complex): (Vec<_>, Vec<_>) = regions
+            .iter()
+            .map(|region| (region, region.convex_parts()))
+            .partition(|(_, parts)| parts.is_some());
+        let polygons: Vec<Vec<(f64, f64)>> = outline
+            .iter()
+            .flat_map(|subpath| {
+                // overlapping parts of a clip draw the same fill twice
+                convex.iter().flat_map(|(_, parts)| parts).fold(
+                    vec![subpath.polygon()],
+                    |pieces, parts| {
+                        pieces
+                            .iter()
+                            .flat_map(|piece| parts.iter().map(|part| clip_polygon(piece, part)))
+                            .filter(|piece| piece.len() >= 3)
+                            .collect()
+                    },
+                )
+            })
+            .filter(|poly| poly.len() >= 3)
+            .collect();
+        let fill = SvgStyle {
+            fill: style.fill.clone(),
+            fill_rule: style.fill_rule.clone(),
+            ..Default::default()
+        };
+        // other clips cut the fill to their bounds in a box
+        let bounds = complex
+            .iter()
+            .map(|(region, _)| region.bounds())
+            .reduce(|a, b| intersect(a?, b?));
+        match bounds {
+            None => emitter.polylines(&polygons, true, &fill),
+            Some(Some([x0, y0, x1, y1])) if !polygons.is_empty() => {
+                warn!("Fill clipped to the bounds of a clip path that isn't a simple polygon");
+                emitter.clipped_fill(&polygons, &fill, (x0, y0), (x1, y1));
+            }
+            Some(_) => {}
+        }
+    }
+    // without a style the pieces are stroked the way the whole outline would be
+    let stroke = match style {
+        Some(style) => {
+            let stroked = style.stroke.is_some()
+                || style.stroke_width.is_some()
+                || style.dash_array.is_some();
+            if !stroked || style.stroke.as_ref().is_some_and(|s| s == "none") {
+                return;
+            }
+            Some(SvgStyle {
+                fill: None,
+                ..style.clone()
+            })
+        }
+        None => None,
+    };
+    for subpath in outline {
+        // pieces that continue where the previous one ended are stroked as one
+        let mut runs: Vec<Vec<Segment>> = Vec::new();
+        let mut joined = false;
+        for segment in &subpath.segments {
+            let parts = visible_parts(segment, regions);
+            for &(t0, t1) in &parts {
+                let piece = segment.split(t0, t1);
+                match runs.last_mut() {
+                    Some(run) if joined && t0 == 0.0 => run.push(piece),
+                    _ => runs.push(vec![piece]),
+                }
+                joined = t1 == 1.0;
+            }
+            if parts.is_empty() {
+                joined = false;
+            }
+        }
+        // a closed outline that is cut somewhere may start in the middle of a run
+        if subpath.closed
+            && joined
+            && runs.len() > 1
+            && visible_parts(&subpath.segments[0], regions)
+                .first()
+                .is_some_and(|p| p.0 == 0.0)
+        {
+            let first = runs.remove(0);
+            runs.last_mut().unwrap().extend(first);
+        }
+        let whole = runs.len() == 1 && runs[0].len() == subpath.segments.len() && joined;
+        for segments in runs {
+            let run = Subpath {
+                segments,
+                closed: subpath.closed && whole,
+            };
+            emitter.path(&[run], stroke.as_ref(), None);
+        }
+    }
+}
+
+/// The overlap of two rectangles given by their lower left and upper right corners
+pub fn intersect(a: [f64; 4], b: [f64; 4]) -> Option<[f64; 4]> {
+    let overlap = [
+        a[0].max(b[0]),
+        a[1].max(b[1]),
+        a[2].min(b[2]),
+        a[3].min(b[3]),
+    ];
+    (overlap[0] < overlap[2] && overlap[1] < overlap[3]).then_some(overlap)
+}
+
+/// Parameter ranges of a segment inside all regions
+fn visible_parts(segment: &Segment, regions: &[Region]) -> Vec<(f64, f64)> {
+    let mut ts = vec![0.0, 1.0];
+    for (c, d) in regions.iter().flat_map(|r| r.edges()) {
+        ts.extend(crossings(segment, c, d));
+    }
+    ts.sort_by(f64::total_cmp);
+    ts.dedup_by(|a, b| (*a - *b).abs() < 1e-9);
+    *ts.last_mut().unwrap() = 1.0;
+    let mut parts: Vec<(f64, f64)> = Vec::new();
+    for w in ts.windows(2) {
+        let mid = segment.point_at((w[0] + w[1]) / 2.0);
+        if !regions.iter().all(|r| r.contains(mid)) {
+            continue;
+        }
+        match parts.last_mut() {
+            Some(last) if last.1 == w[0] => last.1 = w[1],
+            _ => parts.push((w[0], w[1])),
+        }
+    }
+    parts
+}
+
+/// Parameters where a segment crosses the edge from `c` to `d`
+fn crossings(segment: &Segment, c: (f64, f64), d: (f64, f64)) -> Vec<f64> {
+    let edge = (d.0 - c.0, d.1 - c.1);
+    let len2 = edge.0 * edge.0 + edge.1 * edge.1;
+    if len2 == 0.0 {
+        return Vec::new();
+    }
+    // signed distance from the edge's line, and position along the edge
+    let side = |p: (f64, f64)| cross(edge, (p.0 - c.0, p.1 - c.1));
+    let on_edge = |p: (f64, f64)| {
+        let s = ((p.0 - c.0) * edge.0 + (p.1 - c.1) * edge.1) / len2;
+        (-1e-9..=1.0 + 1e-9).contains(&s)
+    };
+    let mut ts = Vec::new();
+    let steps = match segment {
+        Segment::Line(..) => 1,
+        Segment::Cubic(..) => CROSSING_STEPS,
+    };
+    let mut t0 = 0.0;
+    let mut f0 = side(segment.point_at(0.0));
+    for i in 1..=steps {
+        let t1 = i as f64 / steps as f64;
+        let f1 = side(segment.point_at(t1));
+        if f0 * f1 < 0.0 {
+            let (mut lo, mut hi, mut flo) = (t0, t1, f0);
+            if let Segment::Line(..) = segment {
+                lo = f0 / (f0 - f1);
+                hi = lo;
+            } else {
+                for _ in 0..40 {
+                    let mid = (lo + hi) / 2.0;
+                    let fmid = side(segment.point_at(mid));
+                    if flo * fmid <= 0.0 {
+                        hi = mid;
+                    } else {
+                        (lo, flo) = (mid, fmid);
+                    }
+                }
+            }
+            let t = (lo + hi) / 2.0;
+            if on_edge(segment.point_at(t)) {
+                ts.push(t);
+            }
+        }
+        (t0, f0) = (t1, f1);
+    }
+    ts
+}
+
+/// Sutherland–Hodgman clipping of a polygon by a convex one
+fn clip_polygon(polygon: &[(f64, f64)], clip: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let orientation = signed_area(clip).signum();
+    let mut output = polygon.to_vec();
+    for i in 0..clip.len() {
+        let (a, b) = (clip[i], clip[(i + 1) % clip.len()]);
+        if a == b || output.is_empty() {
+            continue;
+        }
+        let inside = |p: (f64, f64)| {
+            orientation * cross((b.0 - a.0, b.1 - a.1), (p.0 - a.0, p.1 - a.1)) >= 0.0
+        };
+        let input = std::mem::take(&mut output);
+        for j in 0..input.len() {
+            let (p, q) = (input[j], input[(j + 1) % input.len()]);
+            if inside(p) {
+                output.push(p);
+            }
+            if inside(p) != inside(q) {
+                let fp = cross((b.0 - a.0, b.1 - a.1), (p.0 - a.0, p.1 - a.1));
+                let fq = cross((b.0 - a.0, b.1 - a.1), (q.0 - a.0, q.1 - a.1));
+                let t = fp / (fp - fq);
+                output.push((p.0 + (q.0 - p.0) * t, p.1 + (q.1 - p.1) * t));
+            }
+        }
+    }
+    output
+}
+
+/// Splits a simple polygon into convex ones, by cutting off ears and merging the
+/// triangles back together where they stay convex. Fails on polygons that cross
+/// themselves.
+fn convex_pieces(polygon: &[(f64, f64)]) -> Option<Vec<Vec<(f64, f64)>>> {
+    let mut points = polygon.to_vec();
+    points.dedup();
+    while points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    let n = points.len();
+    if n < 3 || signed_area(&points).abs() < 1e-12 {
+        return None;
+    }
+    let edges_cross = |i: usize, j: usize| {
+        let (a, b) = (points[i], points[(i + 1) % n]);
+        let (c, d) = (points[j], points[(j + 1) % n]);
+        let side = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| {
+            cross((q.0 - p.0, q.1 - p.1), (r.0 - p.0, r.1 - p.1))
+        };
+        side(a, b, c) * side(a, b, d) < 0.0 && side(c, d, a) * side(c, d, b) < 0.0
+    };
+    let adjacent = |i: usize, j: usize| j == i + 1 || (i == 0 && j == n - 1);
+    if (0..n).any(|i| (i + 1..n).any(|j| !adjacent(i, j) && edges_cross(i, j))) {
+        return None;
+    }
+    if signed_area(&points) < 0.0 {
+        points.reverse();
+    }
+    // ear clipping, counterclockwise
+    let mut remaining = points;
+    let mut pieces: Vec<Vec<(f64, f64)>> = Vec::new();
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let corner = |i: usize| {
+            (
+                remaining[(i + m - 1) % m],
+                remaining[i],
+                remaining[(i + 1) % m],
+            )
+        };
+        let turn = |(a, b, c): ((f64, f64), (f64, f64), (f64, f64))| {
+            cross((b.0 - a.0, b.1 - a.1), (c.0 - b.0, c.1 - b.1))
+        };
+        // straight corners are dropped without a triangle
+        if let Some(i) = (0..m).find(|&i| turn(corner(i)).abs() < 1e-12) {
+            remaining.remove(i);
+            continue;
+        }
+        let ear = (0..m).find(|&i| {
+            let (a, b, c) = corner(i);
+            turn((a, b, c)) > 0.0
+                && remaining.iter().all(|&p| {
+                    p == a
+                        || p == b
+                        || p == c
+                        || !(turn((a, b, p)) >= 0.0
+                            && turn((b, c, p)) >= 0.0
+                            && turn((c, a, p)) >= 0.0)
+                })
+        })?;
+        let (a, b, c) = corner(ear);
+        pieces.push(vec![a, b, c]);
+        remaining.remove(ear);
+    }
+    pieces.push(remaining);
+    // merge pieces along shared edges while the result is convex
+    'merge: loop {
+        for i in 0..pieces.len() {
+            for j in i + 1..pieces.len() {
+                if let Some(merged) = merge_along_edge(&pieces[i], &pieces[j])
+                    && is_convex(&merged)
+                {
+                    pieces[i] = merged;
+                    pieces.remove(j);
+                    continue 'merge;
+                }
+            }
+        }
+        break;
+    }
+    Some(pieces)
+}
+
+/// Joins two polygons of the same orientation that share an edge
+fn merge_along_edge(p: &[(f64, f64)], q: &[(f64, f64)]) -> Option<Vec<(f64, f64)>> {
+    let (n, m) = (p.len(), q.len());
+    for i in 0..n {
+        let (a, b) = (p[i], p[(i + 1) % n]);
+        let Some(j) = (0..m).find(|&j| q[j] == b && q[(j + 1) % m] == a) else {
+            continue;
+        };
+        // around p from b back to a, then around q from a back to b
+        let mut merged: Vec<(f64, f64)> = (1..=n).map(|k| p[(i + k) % n]).collect();
+        merged.extend((2..m).map(|k| q[(j + k) % m]));
+        return Some(merged);
+    }
+    None
+}
+
+/// Whether a closed polygon turns the same way at every corner
+pub fn is_convex(polygon: &[(f64, f64)]) -> bool {
+    let n = polygon.len();
+    let mut sign = 0.0;
+    for i in 0..n {
+        let (a, b, c) = (polygon[i], polygon[(i + 1) % n], polygon[(i + 2) % n]);
+        let turn = cross((b.0 - a.0, b.1 - a.1), (c.0 - b.0, c.1 - b.1));
+        if turn.abs() < 1e-9 {
+            continue;
+        }
+        if sign != 0.0 && turn.signum() != sign {
+            return false;
+        }
+        sign = turn.signum();
+    }
+    n >= 3
+}
+
+/// Crossing-number test of a point against a closed polygon
+fn winds_around(polygon: &[(f64, f64)], p: (f64, f64)) -> bool {
+    let mut inside = false;
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[(i + 1) % polygon.len()]);
+        if (a.1 > p.1) != (b.1 > p.1) && p.0 < a.0 + (p.1 - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+fn signed_area(polygon: &[(f64, f64)]) -> f64 {
+    (0..polygon.len())
+        .map(|i| cross(polygon[i], polygon[(i + 1) % polygon.len()]))
+        .sum::<f64>()
+        / 2.0
+}
+
+fn cross(a: (f64, f64), b: (f64, f64)) -> f64 {
+    a.0 * b.1 - a.1 * b.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x0: f64, y0: f64, x1: f64, y1: f64) -> Vec<(f64, f64)> {
+        vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1)]
+    }
+
+    fn region(polygon: Vec<(f64, f64)>) -> Region {
+        Region {
+            shapes: vec![Polylines(vec![polygon])],
+        }
+    }
+
+    fn area(pieces: &[Vec<(f64, f64)>]) -> f64 {
+        pieces.iter().map(|p| signed_area(p).abs()).sum()
+    }
+
+    fn outline(polygon: &[(f64, f64)]) -> Vec<Subpath> {
+        vec![Subpath {
+            segments: (0..polygon.len())
+                .map(|i| Segment::Line(polygon[i], polygon[(i + 1) % polygon.len()]))
+                .collect(),
+            closed: true,
+        }]
+    }
+
+    #[test]
+    fn clip_polygon_to_square() {
+        let clipped = clip_polygon(&square(0.0, 0.0, 2.0, 2.0), &square(1.0, 1.0, 3.0, 3.0));
+        assert_eq!(clipped.len(), 4);
+        assert!((signed_area(&clipped) - 1.0).abs() < 1e-9);
+        // the clip's orientation doesn't matter
+        let mut clockwise = square(1.0, 1.0, 3.0, 3.0);
+        clockwise.reverse();
+        let clipped = clip_polygon(&square(0.0, 0.0, 2.0, 2.0), &clockwise);
+        assert!((signed_area(&clipped) - 1.0).abs() < 1e-9);
+        assert!(clip_polygon(&square(0.0, 0.0, 1.0, 1.0), &square(2.0, 2.0, 3.0, 3.0)).is_empty());
+    }
+
+    #[test]
+    fn visible_parts_of_a_line() {
+        let regions = [region(square(0.0, 0.0, 2.0, 2.0))];
+        let parts = visible_parts(&Segment::Line((-1.0, 1.0), (3.0, 1.0)), &regions);
+        assert_eq!(parts.len(), 1);
+        assert!((parts[0].0 - 0.25).abs() < 1e-9 && (parts[0].1 - 0.75).abs() < 1e-9);
+        let outside = Segment::Line((-1.0, 3.0), (3.0, 3.0));
+        assert!(visible_parts(&outside, &regions).is_empty());
+        let inside = Segment::Line((0.5, 0.5), (1.5, 1.5));
+        assert_eq!(visible_parts(&inside, &regions), [(0.0, 1.0)]);
+    }
+
+    #[test]
+    fn visible_parts_in_all_regions() {
+        let regions = [
+            region(square(0.0, 0.0, 2.0, 2.0)),
+            region(square(1.0, 0.0, 3.0, 2.0)),
+        ];
+        let parts = visible_parts(&Segment::Line((-1.0, 1.0), (3.0, 1.0)), &regions);
+        assert_eq!(parts.len(), 1);
+        assert!((parts[0].0 - 0.5).abs() < 1e-9 && (parts[0].1 - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn covers_only_shapes_inside_the_clip() {
+        let regions = [region(square(0.0, 0.0, 4.0, 4.0))];
+        assert!(covers(&regions, &outline(&square(1.0, 1.0, 2.0, 2.0))));
+        assert!(!covers(&regions, &outline(&square(3.0, 3.0, 5.0, 5.0))));
+        // a clip within the shape cuts it without crossing its outline
+        let small = [region(square(1.0, 1.0, 2.0, 2.0))];
+        assert!(!covers(&small, &outline(&square(0.0, 0.0, 4.0, 4.0))));
+    }
+
+    #[test]
+    fn concave_polygons_split_into_convex_pieces() {
+        // an L shape
+        let l = vec![
+            (0.0, 0.0),
+            (2.0, 0.0),
+            (2.0, 1.0),
+            (1.0, 1.0),
+            (1.0, 2.0),
+            (0.0, 2.0),
+        ];
+        let pieces = convex_pieces(&l).unwrap();
+        assert_eq!(pieces.len(), 2);
+        assert!(pieces.iter().all(|p| is_convex(p)));
+        assert!((area(&pieces) - 3.0).abs() < 1e-9);
+        let star: Vec<(f64, f64)> = (0..10)
+            .map(|i| {
+                let r = if i % 2 == 0 { 2.0 } else { 1.0 };
+                let a = i as f64 * std::f64::consts::PI / 5.0;
+                (r * a.cos(), r * a.sin())
+            })
+            .collect();
+        let pieces = convex_pieces(&star).unwrap();
+        assert!(pieces.iter().all(|p| is_convex(p)));
+        assert!((area(&pieces) - signed_area(&star).abs()).abs() < 1e-9);
+        // a polygon crossing itself
+        let bowtie = vec![(0.0, 0.0), (1.0, 1.0), (1.0, 0.0), (0.0, 1.0)];
+        assert!(convex_pieces(&bowtie).is_none());
+    }
+}
@@ -0,0 +1,536 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::Result;
+use log::{debug, warn};
+use quick_xml::{
+    Reader,
+    events::{BytesStart, Event},
+};
+use svgtypes::{Color, Transform};
+
+use crate::{
+    geometry::{Polylines, shape_bounds, shape_polylines},
+    parse_size, parse_size_list,
+};
+
+/// Elements referenced by id from elsewhere in the document. They are collected in
+/// a pass of their own, as references may point forward and into `defs`.
+#[derive(Debug, Default)]
+pub struct Definitions {
+    paths: HashMap<String, PathDef>,
+    // bounds of shapes, as x, y, width and height
+    bounds: HashMap<String, [f64; 4]>,
+    clip_paths: HashMap<String, ClipPathDef>,
+    gradients: HashMap<String, GradientDef>,
+    masks: HashMap<String, MaskDef>,
+    markers: HashMap<String, MarkerDef>,
+}
+
+/// A `clipPath` element with its shapes flattened
+#[derive(Debug, Clone, Default)]
+pub struct ClipPathDef {
+    /// Whether `clipPathUnits` is `objectBoundingBox`
+    pub bounding_box: bool,
+    pub transform: Transform,
+    /// The shapes in the coordinate system of the clip path
+    pub shapes: Vec<Polylines>,
+}
+
+/// A `path` element that may be referenced
+#[derive(Debug, Clone)]
+pub struct PathDef {
+    pub d: String,
+    /// The path's own transform, not those of its ancestors
+    pub transform: Transform,
+}
+
+/// A `linearGradient` or `radialGradient` element
+#[derive(Debug, Clone, Default)]
+pub struct GradientDef {
+    pub radial: bool,
+    /// Whether `gradientUnits` is `userSpaceOnUse`
+    pub user_space: bool,
+    /// `x1`, `y1`, `x2` and `y2` or `cx`, `cy` and `r`, as numbers or fractions
+    pub coords: HashMap<String, f64>,
+    pub stops: Vec<Stop>,
+    // gradient the stops are taken from when there are none
+    href: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stop {
+    pub offset: f64,
+    pub color: Color,
+    pub opacity: f64,
+}
+
+impl GradientDef {
+    /// The same gradient in user space, for one in bounding box units on a shape
+    /// with bounds `bbox`.
+    pub fn to_user_space(&self, [x, y, w, h]: [f64; 4]) -> GradientDef {
+        if self.user_space {
+            return self.clone();
+        }
+        let mut gradient = self.clone();
+        gradient.user_space = true;
+        for name in ["x1", "x2", "cx"] {
+            gradient
+                .coords
+                .insert(name.to_string(), x + self.coord(name) * w);
+        }
+        for name in ["y1", "y2", "cy"] {
+            gradient
+                .coords
+                .insert(name.to_string(), y + self.coord(name) * h);
+        }
+        gradient.coords.insert("r".to_string(), self.coord("r") * w);
+        gradient
+    }
+
+    /// A coordinate, with the defaults of the spec for missing ones
+    pub fn coord(&self, name: &str) -> f64 {
+        self.coords.get(name).copied().unwrap_or(match name {
+            "x2" => 1.0,
+            "cx" | "cy" | "r" => 0.5,
+            _ => 0.0,
+        })
+    }
+}
+
+/// A `mask` element, with the paint of each of its shapes
+#[derive(Debug, Clone, Default)]
+pub struct MaskDef {
+    /// `mask-type: alpha`, rather than luminance
+    pub alpha: bool,
+    pub paints: Vec<MaskPaint>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaskPaint {
+    pub fill: String,
+    /// `opacity` and `fill-opacity` combined
+    pub opacity: f64,
+    /// Bounds of the shape, for gradients in bounding box units
+    pub bounds: Option<[f64; 4]>,
+    /// The outline of the shape, when it is a single polygon
+    pub polygon: Option<Vec<(f64, f64)>>,
+}
+
+/// A `marker` element
+#[derive(Debug, Clone)]
+pub struct MarkerDef {
+    pub ref_x: f64,
+    pub ref_y: f64,
+    pub width: f64,
+    pub height: f64,
+    /// Whether `markerUnits` is `strokeWidth`, the default
+    pub stroke_units: bool,
+    pub orient: Orient,
+    pub view_box: Option<[f64; 4]>,
+    pub shapes: Vec<MarkerShape>,
+}
+
+impl Default for MarkerDef {
+    fn default() -> Self {
+        MarkerDef {
+            ref_x: 0.0,
+            ref_y: 0.0,
+            width: 3.0,
+            height: 3.0,
+            stroke_units: true,
+            orient: Orient::Angle(0.0),
+            view_box: None,
+            shapes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orient {
+    Auto,
+    AutoStartReverse,
+    /// Fixed angle in degrees
+    Angle(f64),
+}
+
+/// A shape inside a marker, flattened, with its paint
+#[derive(Debug, Clone)]
+pub struct MarkerShape {
+    /// Element name, like `path` or `circle`
+    pub kind: Vec<u8>,
+    pub lines: Polylines,
+    pub closed: bool,
+    pub fill: Option<String>,
+    pub stroke: Option<String>,
+    pub stroke_width: Option<f64>,
+}
+
+impl PathDef {
+    pub fn polylines(&self) -> Result<Polylines> {
+        Polylines::from_path_data(&self.d, &self.transform)
+    }
+}
+
+impl Definitions {
+    pub fn collect(input: &str) -> Result<Self> {
+        let mut defs = Definitions::default();
+        let mut reader = Reader::from_str(input);
+        // ids of the clip path, mask and gradient being read
+        let mut clip_path = None;
+        let mut mask = None;
+        let mut gradient = None;
+        let mut marker = None;
+        loop {
+            let event = reader.read_event()?;
+            if let Some(id) = &clip_path
+                && let Event::Start(element) | Event::Empty(element) = &event
+                && let Some(shape) = shape_polylines(element)?
+            {
+                defs.clip_paths.get_mut(id).unwrap().shapes.push(shape);
+            }
+            if let Some(id) = &mask
+                && let Event::Start(element) | Event::Empty(element) = &event
+                && let Some(lines) = shape_polylines(element)?
+            {
+                let paint = mask_paint(element, lines)?;
+                defs.masks.get_mut(id).unwrap().paints.extend(paint);
+            }
+            if let Some(id) = &gradient
+                && let Event::Start(element) | Event::Empty(element) = &event
+                && element.name().as_ref() == b"stop"
+            {
+                let stop = read_stop(element)?;
+                defs.gradients.get_mut(id).unwrap().stops.push(stop);
+            }
+            if let Some(id) = &marker
+                && let Event::Start(element) | Event::Empty(element) = &event
+                && let Some(lines) = shape_polylines(element)?
+            {
+                let shape = marker_shape(element, lines)?;
+                defs.markers.get_mut(id).unwrap().shapes.push(shape);
+            }
+            let has_content = matches!(event, Event::Start(_));
+            match event {
+                Event::Start(element) | Event::Empty(element)
+                    if matches!(
+                        element.name().as_ref(),
+                        b"linearGradient" | b"radialGradient"
+                    ) =>
+                {
+                    let Some(id) = element.try_get_attribute(b"id")? else {
+                        continue;
+                    };
+                    let mut def = GradientDef {
+                        radial: element.name().as_ref() == b"radialGradient",
+                        ..Default::default()
+                    };
+                    for attr in element.attributes() {
+                        let attr = attr?;
+                        let value = attr.unescape_value()?;
+                        match attr.key.as_ref() {
+                            b"gradientUnits" => def.user_space = value == "userSpaceOnUse",
+                            b"href" | b"xlink:href" => def.href = Some(value.to_string()),
+                            key @ (b"x1" | b"y1" | b"x2" | b"y2" | b"cx" | b"cy" | b"r") => {
+                                let coord = match value.strip_suffix('%') {
+                                    Some(percent) => f64::from_str(percent)? / 100.0,
+                                    None => parse_size(&value, 1.0)?,
+                                };
+                                def.coords.insert(String::from_utf8(key.to_vec())?, coord);
+                            }
+                            b"gradientTransform" => debug!("Unprocessed gradientTransform"),
+                            _ => {}
+                        }
+                    }
+                    let id = id.unescape_value()?.to_string();
+                    defs.gradients.insert(id.clone(), def);
+                    if has_content {
+                        gradient = Some(id);
+                    }
+                }
+                Event::End(element)
+                    if matches!(
+                        element.name().as_ref(),
+                        b"linearGradient" | b"radialGradient"
+                    ) =>
+                {
+                    gradient = None
+                }
+                Event::Start(element) if element.name().as_ref() == b"mask" => {
+                    let Some(id) = element.try_get_attribute(b"id")? else {
+                        continue;
+                    };
+                    let alpha = element
+                        .try_get_attribute(b"mask-type")?
+                        .is_some_and(|t| t.value.as_ref() == b"alpha")
+                        || property(&element, "mask-type")?.is_some_and(|t| t == "alpha");
+                    let id = id.unescape_value()?.to_string();
+                    defs.masks.insert(
+                        id.clone(),
+                        MaskDef {
+                            alpha,
+                            paints: Vec::new(),
+                        },
+                    );
+                    mask = Some(id);
+                }
+                Event::End(element) if element.name().as_ref() == b"mask" => mask = None,
+                Event::Start(element) if element.name().as_ref() == b"marker" => {
+                    let Some(id) = element.try_get_attribute(b"id")? else {
+                        continue;
+                    };
+                    let mut def = MarkerDef::default();
+                    for attr in element.attributes() {
+                        let attr = attr?;
+                        let value = attr.unescape_value()?;
+                        match attr.key.as_ref() {
+                            b"refX" => def.ref_x = parse_size(&value, 1.0)?,
+                            b"refY" => def.ref_y = parse_size(&value, 1.0)?,
+                            b"markerWidth" => def.width = parse_size(&value, 1.0)?,
+                            b"markerHeight" => def.height = parse_size(&value, 1.0)?,
+                            b"markerUnits" => def.stroke_units = value != "userSpaceOnUse",
+                            b"orient" => {
+                                def.orient = match value.as_ref() {
+                                    "auto" => Orient::Auto,
+                                    "auto-start-reverse" => Orient::AutoStartReverse,
+                                    angle => Orient::Angle(
+                                        f64::from_str(angle.trim_end_matches("deg")).unwrap_or(0.0),
+                                    ),
+                                }
+                            }
+                            b"viewBox" => {
+                                let numbers = parse_size_list(&value, 1.0)?;
+                                if let [x, y, w, h] = numbers[..] {
+                                    def.view_box = Some([x, y, w, h]);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    let id = id.unescape_value()?.to_string();
+                    defs.markers.insert(id.clone(), def);
+                    marker = Some(id);
+                }
+                Event::End(element) if element.name().as_ref() == b"marker" => marker = None,
+                Event::Start(element) if element.name().as_ref() == b"clipPath" => {
+                    let Some(id) = element.try_get_attribute(b"id")? else {
+                        continue;
+                    };
+                    let mut def = ClipPathDef::default();
+                    for attr in element.attributes() {
+                        let attr = attr?;
+                        match attr.key.as_ref() {
+                            b"clipPathUnits" => {
+                                def.bounding_box = attr.value.as_ref() == b"objectBoundingBox";
+                            }
+                            b"transform" => {
+                                def.transform = Transform::from_str(&attr.unescape_value()?)?
+                            }
+                            _ => {}
+                        }
+                    }
+                    let id = id.unescape_value()?.to_string();
+                    defs.clip_paths.insert(id.clone(), def);
+                    clip_path = Some(id);
+                }
+                Event::End(element) if element.name().as_ref() == b"clipPath" => clip_path = None,
+                Event::Start(element) | Event::Empty(element)
+                    if matches!(element.name().as_ref(), b"path" | b"rect") =>
+                {
+                    // bounds are only used by shape-inside, which most shapes aren't
+                    // referenced from, so those that can't be measured have none
+                    if let Some(id) = element.try_get_attribute(b"id")? {
+                        let id = id.unescape_value()?.to_string();
+                        match shape_bounds(&element) {
+                            Ok(Some(bounds)) => {
+                                defs.bounds.insert(id, bounds);
+                            }
+                            Ok(None) => {}
+                            Err(err) => debug!("No bounds for #{}: {}", id, err),
+                        }
+                    }
+                    let (Some(id), Some(d)) = (
+                        element.try_get_attribute(b"id")?,
+                        element.try_get_attribute(b"d")?,
+                    ) else {
+                        continue;
+                    };
+                    let transform = match element.try_get_attribute(b"transform")? {
+                        Some(t) => Transform::from_str(&t.unescape_value()?)?,
+                        None => Transform::default(),
+                    };
+                    defs.paths.insert(
+                        id.unescape_value()?.to_string(),
+                        PathDef {
+                            d: d.unescape_value()?.to_string(),
+                            transform,
+                        },
+                    );
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+        Ok(defs)
+    }
+
+    /// Resolves a local reference like `#curve`.
+    pub fn path(&self, href: &str) -> Option<&PathDef> {
+        self.paths.get(href.strip_prefix('#')?)
+    }
+
+    /// Bounds of a referenced shape, from a reference like `#rect1` or `url(#rect1)`.
+    pub fn bounds(&self, href: &str) -> Option<[f64; 4]> {
+        self.bounds.get(local_id(href)?).copied()
+    }
+
+    /// Resolves a reference like `url(#fade)` to a gradient, with the stops and
+    /// units of the gradients it links to when it has none of its own.
+    pub fn gradient(&self, href: &str) -> Option<GradientDef> {
+        let mut gradient = self.gradients.get(local_id(href)?)?.clone();
+        let mut linked = gradient.href.clone();
+        // links may form a cycle
+        for _ in 0..8 {
+            if !gradient.stops.is_empty() {
+                break;
+            }
+            let Some(parent) = linked.and_then(|h| self.gradients.get(local_id(&h)?)) else {
+                break;
+            };
+            gradient.stops = parent.stops.clone();
+            linked = parent.href.clone();
+        }
+        Some(gradient)
+    }
+
+    pub fn marker(&self, href: &str) -> Option<&MarkerDef> {
+        self.markers.get(local_id(href)?)
+    }
+
+    pub fn mask(&self, href: &str) -> Option<&MaskDef> {
+        self.masks.get(local_id(href)?)
+    }
+
+    /// Resolves a reference like `url(#clip1)` to a clip path.
+    pub fn clip_path(&self, href: &str) -> Option<&ClipPathDef> {
+        self.clip_paths.get(local_id(href)?)
+    }
+}
+
+/// A property from the `style` attribute, or the presentation attribute of
+/// the same name
+pub fn property(element: &BytesStart, name: &str) -> Result<Option<String>> {
+    if let Some(style) = element.try_get_attribute(b"style")? {
+        let style = style.unescape_value()?;
+        for declaration in style.split(';') {
+            if let Some((key, value)) = declaration.split_once(':')
+                && key.trim() == name
+            {
+                return Ok(Some(value.trim().to_string()));
+            }
+        }
+    }
+    match element.try_get_attribute(name.as_bytes())? {
+        Some(a) => Ok(Some(a.unescape_value()?.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn opacity(element: &BytesStart, name: &str) -> Result<f64> {
+    Ok(match property(element, name)? {
+        Some(value) => match value.strip_suffix('%') {
+            Some(percent) => f64::from_str(percent)? / 100.0,
+            None => f64::from_str(&value)?,
+        },
+        None => 1.0,
+    })
+}
+
+fn mask_paint(element: &BytesStart, lines: Polylines) -> Result<Option<MaskPaint>> {
+    // a missing fill is black, which masks everything out
+    let fill = property(element, "fill")?.unwrap_or_else(|| "black".to_string());
+    if fill == "none" {
+        return Ok(None);
+    }
+    Ok(Some(MaskPaint {
+        fill,
+        opacity: opacity(element, "opacity")? * opacity(element, "fill-opacity")?,
+        bounds: shape_bounds(element)?,
+        polygon: match lines.0.as_slice() {
+            [line] => Some(line.clone()),
+            _ => None,
+        },
+    }))
+}
+
+fn marker_shape(element: &BytesStart, lines: Polylines) -> Result<MarkerShape> {
+    let kind = element.name().as_ref().to_vec();
+    let closed = match kind.as_slice() {
+        b"path" => element
+            .try_get_attribute(b"d")?
+            .is_some_and(|d| d.value.iter().any(|c| matches!(c, b'z' | b'Z'))),
+        b"polyline" => false,
+        _ => true,
+    };
+    let stroke_width = match property(element, "stroke-width")? {
+        Some(width) => Some(parse_size(&width, 1.0)?),
+        None => None,
+    };
+    Ok(MarkerShape {
+        kind,
+        lines,
+        closed,
+        // fill defaults to black, stroke to none
+        fill: Some(property(element, "fill")?.unwrap_or_else(|| "black".to_string())),
+        stroke: property(element, "stroke")?,
+        stroke_width,
+    })
+}
+
+/// A gradient stop. Values that can't be read fall back to their defaults, as
+/// the gradient may not even be used.
+fn read_stop(element: &BytesStart) -> Result<Stop> {
+    let offset = match element.try_get_attribute(b"offset")? {
+        Some(a) => {
+            let value = a.unescape_value()?;
+            let offset = match value.strip_suffix('%') {
+                Some(percent) => f64::from_str(percent.trim()).map(|p| p / 100.0),
+                None => f64::from_str(value.trim()),
+            };
+            offset.unwrap_or_else(|_| {
+                warn!("Invalid stop offset {}, using 0", value);
+                0.0
+            })
+        }
+        None => 0.0,
+    };
+    let color = match property(element, "stop-color")?.as_deref() {
+        // the color property of the stop, or its initial value
+        Some("currentColor") => property(element, "color")?
+            .and_then(|color| Color::from_str(&color).ok())
+            .unwrap_or_else(Color::black),
+        Some("inherit") | None => Color::black(),
+        Some(color) => Color::from_str(color).unwrap_or_else(|_| {
+            warn!("Invalid stop color {}, using black", color);
+            Color::black()
+        }),
+    };
+    let opacity = opacity(element, "stop-opacity").unwrap_or_else(|_| {
+        warn!("Invalid stop opacity, using 1");
+        1.0
+    });
+    Ok(Stop {
+        offset: offset.clamp(0.0, 1.0),
+        color,
+        opacity,
+    })
+}
+
+/// The id in a local reference, with or without `url()`
+fn local_id(href: &str) -> Option<&str> {
+    let href = href.trim();
+    let href = href
+        .strip_prefix("url(")
+        .and_then(|h| h.strip_suffix(')'))
+        .unwrap_or(href);
+    href.trim().trim_matches(['"', '\'']).strip_prefix('#')
+}
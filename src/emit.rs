@@ -0,0 +1,469 @@
+use crate::{
+    SvgStyle,
+    geometry::{Segment, Subpath},
+    markers::PathMarkers,
+    typst::string_literal,
+};
+
+/// The kind of Typst code drawings are written as
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+    /// CeTZ draw commands, to be included in a `canvas`
+    #[default]
+    Cetz,
+    /// Plain Typst shapes and content in `place`, without any package
+    Typst,
+}
+
+impl Backend {
+    pub fn emitter(self) -> &'static dyn Emitter {
+        match self {
+            Backend::Cetz => &CetzEmitter,
+            Backend::Typst => &TypstEmitter,
+        }
+    }
+}
+
+/// Writes shapes and content. Points are in output coordinates, in centimeters
+/// with y pointing up.
+pub trait Emitter {
+    /// Whether arrowheads at the ends of a path can be drawn as its marks
+    fn has_marks(&self) -> bool;
+
+    /// An axis-aligned rectangle between two corners
+    fn rect(&self, a: (f64, f64), b: (f64, f64), style: Option<&SvgStyle>);
+
+    fn ellipse(&self, center: (f64, f64), radii: (f64, f64), style: Option<&SvgStyle>);
+
+    fn circle(&self, center: (f64, f64), radius: f64, style: Option<&SvgStyle>);
+
+    /// The subpaths of a path. Fills close open subpaths, strokes only draw the
+    /// segments that are there.
+    fn path(&self, outline: &[Subpath], style: Option<&SvgStyle>, markers: Option<&PathMarkers>);
+
+    /// Polylines drawn as one shape, with holes where they overlap
+    fn polylines(&self, lines: &[Vec<(f64, f64)>], closed: bool, style: &SvgStyle);
+
+    /// Closed polylines filled as one shape and cut to the rectangle between two
+    /// corners, for clip paths that can't be applied to the geometry
+    fn clipped_fill(
+        &self,
+        lines: &[Vec<(f64, f64)>],
+        style: &SvgStyle,
+        a: (f64, f64),
+        b: (f64, f64),
+    );
+
+    /// Typst content with its `anchor` at `pos`, rotated counterclockwise about it
+    fn content(&self, pos: (f64, f64), anchor: &str, angle: f64, body: &str);
+
+    /// A link covering the rectangle between two corners
+    fn link(&self, a: (f64, f64), b: (f64, f64), url: &str);
+}
+
+pub struct CetzEmitter;
+
+impl Emitter for CetzEmitter {
+    fn has_marks(&self) -> bool {
+        true
+    }
+
+    fn rect(&self, a: (f64, f64), b: (f64, f64), style: Option<&SvgStyle>) {
+        print!("rect(({:.3}, {:.3}), ({:.3}, {:.3}), ", a.0, a.1, b.0, b.1);
+        format_style(style);
+        println!(")");
+    }
+
+    fn ellipse(&self, center: (f64, f64), radii: (f64, f64), style: Option<&SvgStyle>) {
+        print!(
+            "circle(({:.3}, {:.3}), radius: ({:.3}, {:.3}), ",
+            center.0, center.1, radii.0, radii.1,
+        );
+        format_style(style);
+        println!(")")
+    }
+
+    fn circle(&self, center: (f64, f64), radius: f64, style: Option<&SvgStyle>) {
+        print!(
+            "circle(({:.3}, {:.3}), radius: {}, ",
+            center.0, center.1, radius
+        );
+        format_style(style);
+        println!(")")
+    }
+
+    fn path(&self, outline: &[Subpath], style: Option<&SvgStyle>, markers: Option<&PathMarkers>) {
+        let filled = style
+            .and_then(|s| s.fill.as_ref())
+            .is_some_and(|f| f != "none");
+        // subpaths of a fill cut holes in each other, so they share one element
+        let compound_path = filled && outline.len() > 1;
+        if compound_path {
+            print!("compound-path(");
+            format_style(style);
+            print!(
+                "{}",
+                style.map(SvgStyle::fill_rule_args).unwrap_or_default()
+            );
+            println!("{{");
+        }
+        for (i, subpath) in outline.iter().enumerate() {
+            // a fill closes an open subpath, but its stroke stays open
+            print!("merge-path(close: {}, ", subpath.closed);
+            format_style(style);
+            // marks go on the start of the first subpath and the end of the last
+            if let Some(mark) = markers.and_then(|m| m.mark(i == 0, i + 1 == outline.len())) {
+                print!("{}", mark);
+            }
+            println!("{{");
+            for segment in &subpath.segments {
+                match segment {
+                    Segment::Line(a, b) => {
+                        println!("line(({:.3}, {:.3}), ({:.3}, {:.3}))", a.0, a.1, b.0, b.1)
+                    }
+                    Segment::Cubic(p0, p1, p2, p3) => println!(
+                        "bezier(({:.3}, {:.3}), ({:.3}, {:.3}), ({:.3}, {:.3}), ({:.3}, {:.3}))",
+                        p0.0, p0.1, p3.0, p3.1, p1.0, p1.1, p2.0, p2.1,
+                    ),
+                }
+            }
+            println!("}})");
+        }
+        if compound_path {
+            println!("}})");
+        }
+    }
+
+    fn polylines(&self, lines: &[Vec<(f64, f64)>], closed: bool, style: &SvgStyle) {
+        let line = |points: &[(f64, f64)]| {
+            print!("line(");
+            for (x, y) in points {
+                print!("({:.3}, {:.3}), ", x, y);
+            }
+            if closed {
+                print!("close: true, ");
+            }
+        };
+        match lines {
+            [] => {}
+            [points] => {
+                line(points);
+                format_style(Some(style));
+                println!(")");
+            }
+            lines => {
+                print!("compound-path(");
+                format_style(Some(style));
+                print!("{}", style.fill_rule_args());
+                println!("{{");
+                for points in lines {
+                    line(points);
+                    println!(")");
+                }
+                println!("}})");
+            }
+        }
+    }
+
+    fn clipped_fill(
+        &self,
+        lines: &[Vec<(f64, f64)>],
+        style: &SvgStyle,
+        a: (f64, f64),
+        b: (f64, f64),
+    ) {
+        println!(
+            "content(({:.3}, {:.3}), anchor: \"north-west\", {})",
+            a.0.min(b.0),
+            a.1.max(b.1),
+            clip_box(lines, style, a, b)
+        );
+    }
+
+    fn content(&self, pos: (f64, f64), anchor: &str, angle: f64, body: &str) {
+        print!("content(({:.3},{:.3}), ", pos.0, pos.1);
+        print!("anchor: \"{}\",", anchor);
+        if angle.abs() > 1e-3 {
+            print!("angle: {:.3}deg, ", angle);
+        }
+        println!("{})", body);
+    }
+
+    fn link(&self, a: (f64, f64), b: (f64, f64), url: &str) {
+        // an invisible box the size of the shapes, for the link to cover them
+        println!(
+            "content(({:.3}, {:.3}), ({:.3}, {:.3}), link({}, box(width: 100%, height: 100%)))",
+            a.0,
+            a.1,
+            b.0,
+            b.1,
+            string_literal(url)
+        );
+    }
+}
+
+/// Places everything relative to the top left corner of the enclosing container,
+/// with y pointing down.
+pub struct TypstEmitter;
+
+impl TypstEmitter {
+    /// Starts a `place` at the top left corner of a box in output coordinates
+    fn place(&self, left: f64, top: f64) {
+        // adding zero turns -0 into 0
+        print!("place(dx: {:.3}cm, dy: {:.3}cm, ", left + 0.0, -top + 0.0);
+    }
+}
+
+impl Emitter for TypstEmitter {
+    fn has_marks(&self) -> bool {
+        false
+    }
+
+    fn rect(&self, a: (f64, f64), b: (f64, f64), style: Option<&SvgStyle>) {
+        self.place(a.0.min(b.0), a.1.max(b.1));
+        print!(
+            "rect(width: {:.3}cm, height: {:.3}cm, ",
+            (b.0 - a.0).abs(),
+            (b.1 - a.1).abs()
+        );
+        format_style(style);
+        println!("))");
+    }
+
+    fn ellipse(&self, center: (f64, f64), radii: (f64, f64), style: Option<&SvgStyle>) {
+        let (rx, ry) = (radii.0.abs(), radii.1.abs());
+        self.place(center.0 - rx, center.1 + ry);
+        print!(
+            "ellipse(width: {:.3}cm, height: {:.3}cm, ",
+            2.0 * rx,
+            2.0 * ry
+        );
+        format_style(style);
+        println!("))");
+    }
+
+    fn circle(&self, center: (f64, f64), radius: f64, style: Option<&SvgStyle>) {
+        let radius = radius.abs();
+        self.place(center.0 - radius, center.1 + radius);
+        print!("circle(radius: {:.3}cm, ", radius);
+        format_style(style);
+        println!("))");
+    }
+
+    fn path(&self, outline: &[Subpath], style: Option<&SvgStyle>, _markers: Option<&PathMarkers>) {
+        let Some(origin) = top_left(
+            outline
+                .iter()
+                .flat_map(|s| s.segments.iter())
+                .flat_map(control_points),
+        ) else {
+            return;
+        };
+        self.place(origin.0, origin.1);
+        // a curve's fill closes open subpaths, but its stroke doesn't
+        println!(
+            "{})",
+            curve(
+                outline.iter().map(|s| (s.segments.as_slice(), s.closed)),
+                origin,
+                style,
+            )
+        );
+    }
+
+    fn polylines(&self, lines: &[Vec<(f64, f64)>], closed: bool, style: &SvgStyle) {
+        let Some(origin) = top_left(lines.iter().flatten().copied()) else {
+            return;
+        };
+        self.place(origin.0, origin.1);
+        if let [points] = lines
+            && closed
+        {
+            print!("polygon(");
+            format_style(Some(style));
+            print!("{}", style.fill_rule_args());
+            for p in points {
+                print!(
+                    "({:.3}cm, {:.3}cm), ",
+                    p.0 - origin.0 + 0.0,
+                    origin.1 - p.1 + 0.0
+                );
+            }
+            println!("))");
+            return;
+        }
+        let segments = line_segments(lines);
+        println!(
+            "{})",
+            curve(
+                segments.iter().map(|s| (s.as_slice(), closed)),
+                origin,
+                Some(style),
+            )
+        );
+    }
+
+    fn clipped_fill(
+        &self,
+        lines: &[Vec<(f64, f64)>],
+        style: &SvgStyle,
+        a: (f64, f64),
+        b: (f64, f64),
+    ) {
+        self.place(a.0.min(b.0), a.1.max(b.1));
+        println!("{})", clip_box(lines, style, a, b));
+    }
+
+    fn content(&self, pos: (f64, f64), anchor: &str, angle: f64, body: &str) {
+        // the fraction of the content's size that lies left of and above the anchor
+        let fx = if anchor.contains("west") {
+            0.0
+        } else if anchor.contains("east") {
+            1.0
+        } else {
+            0.5
+        };
+        let fy = if anchor.contains("north") {
+            0.0
+        } else if anchor.contains("south") {
+            1.0
+        } else {
+            0.5
+        };
+        let rotate = |body: &str| {
+            if angle.abs() <= 1e-3 {
+                return body.to_string();
+            }
+            // typst rotates clockwise
+            format!(
+                "rotate({:.3}deg, origin: {} + {}, reflow: false, {})",
+                -angle,
+                ["top", "horizon", "bottom"][(fy * 2.0) as usize],
+                ["left", "center", "right"][(fx * 2.0) as usize],
+                body
+            )
+        };
+        self.place(pos.0, pos.1);
+        if fx == 0.0 && fy == 0.0 {
+            println!("{})", rotate(body));
+            return;
+        }
+        let shift = |fraction: f64, dimension: &str| {
+            if fraction == 0.0 {
+                "0pt".to_string()
+            } else {
+                format!("-{} * size.{}", fraction, dimension)
+            }
+        };
+        println!(
+            "context {{ let body = {}; let size = measure(body); move(dx: {}, dy: {}, {}) }})",
+            body,
+            shift(fx, "width"),
+            shift(fy, "height"),
+            rotate("body")
+        );
+    }
+
+    fn link(&self, a: (f64, f64), b: (f64, f64), url: &str) {
+        self.place(a.0.min(b.0), a.1.max(b.1));
+        println!(
+            "link({}, box(width: {:.3}cm, height: {:.3}cm)))",
+            string_literal(url),
+            (b.0 - a.0).abs(),
+            (b.1 - a.1).abs()
+        );
+    }
+}
+
+fn format_style(style: Option<&SvgStyle>) {
+    if let Some(style) = style {
+        style.format_fill();
+        style.format_stroke();
+    }
+}
+
+/// A Typst curve through subpaths, with points relative to the top left corner
+/// `origin`
+fn curve<'a>(
+    subpaths: impl Iterator<Item = (&'a [Segment], bool)>,
+    origin: (f64, f64),
+    style: Option<&SvgStyle>,
+) -> String {
+    let point = |p: &(f64, f64)| {
+        format!(
+            "({:.3}cm, {:.3}cm)",
+            p.0 - origin.0 + 0.0,
+            origin.1 - p.1 + 0.0
+        )
+    };
+    let mut curve = "curve(".to_string();
+    if let Some(style) = style {
+        curve.push_str(&style.fill_args());
+        curve.push_str(&style.stroke_args());
+        curve.push_str(&style.fill_rule_args());
+    }
+    for (segments, closed) in subpaths {
+        let Some(first) = segments.first() else {
+            continue;
+        };
+        curve.push_str(&format!("curve.move({}), ", point(&first.start())));
+        for segment in segments {
+            curve.push_str(&match segment {
+                Segment::Line(_, b) => format!("curve.line({}), ", point(b)),
+                Segment::Cubic(_, p1, p2, p3) => {
+                    format!("curve.cubic({}, {}, {}), ", point(p1), point(p2), point(p3))
+                }
+            });
+        }
+        if closed {
+            curve.push_str("curve.close(mode: \"straight\"), ");
+        }
+    }
+    curve.push(')');
+    curve
+}
+
+/// A box the size of the rectangle between two corners that cuts off the fill
+/// of closed polylines
+fn clip_box(lines: &[Vec<(f64, f64)>], style: &SvgStyle, a: (f64, f64), b: (f64, f64)) -> String {
+    let (left, top) = (a.0.min(b.0), a.1.max(b.1));
+    let segments = line_segments(lines);
+    let fill = curve(
+        segments.iter().map(|s| (s.as_slice(), true)),
+        (left, top),
+        Some(style),
+    );
+    format!(
+        "box(width: {:.3}cm, height: {:.3}cm, clip: true, {})",
+        (b.0 - a.0).abs(),
+        (b.1 - a.1).abs(),
+        fill
+    )
+}
+
+fn line_segments(lines: &[Vec<(f64, f64)>]) -> Vec<Vec<Segment>> {
+    lines
+        .iter()
+        .map(|points| {
+            points
+                .windows(2)
+                .map(|w| Segment::Line(w[0], w[1]))
+                .collect()
+        })
+        .collect()
+}
+
+fn control_points(segment: &Segment) -> Vec<(f64, f64)> {
+    match *segment {
+        Segment::Line(a, b) => vec![a, b],
+        Segment::Cubic(p0, p1, p2, p3) => vec![p0, p1, p2, p3],
+    }
+}
+
+/// The top left corner of the bounding box of points
+fn top_left(points: impl Iterator<Item = (f64, f64)>) -> Option<(f64, f64)> {
+    points.fold(None, |corner, p| match corner {
+        None => Some(p),
+        Some((x, y)) => Some((x.min(p.0), y.max(p.1))),
+    })
+}
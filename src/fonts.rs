@@ -0,0 +1,134 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{Result, anyhow};
+
+use crate::typst::string_literal;
+
+/// Maps CSS font families to the fonts typst should use instead.
+///
+/// Keys are matched case-insensitively. A family mapped to an empty list is dropped,
+/// and unmapped families are passed through unchanged, except for generic families
+/// which typst doesn't know about.
+#[derive(Debug, Clone)]
+pub struct FontMap {
+    map: HashMap<String, Vec<String>>,
+}
+
+const GENERIC_FAMILIES: [&str; 8] = [
+    "serif",
+    "sans-serif",
+    "monospace",
+    "cursive",
+    "fantasy",
+    "system-ui",
+    "math",
+    "emoji",
+];
+
+impl Default for FontMap {
+    fn default() -> Self {
+        let mut map = HashMap::new();
+        // the only generic family typst ships a font for
+        map.insert(
+            "monospace".to_string(),
+            vec!["DejaVu Sans Mono".to_string()],
+        );
+        FontMap { map }
+    }
+}
+
+impl FontMap {
+    /// Adds a `family=font[,font...]` entry, replacing any existing mapping.
+    pub fn insert_entry(&mut self, entry: &str) -> Result<()> {
+        let (family, fonts) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("font mapping should be `family=font[,font...]`: {}", entry))?;
+        let family = parse_font_families(family);
+        let [family] = family.as_slice() else {
+            return Err(anyhow!("font mapping needs exactly one family: {}", entry));
+        };
+        self.map
+            .insert(family.to_lowercase(), parse_font_families(fonts));
+        Ok(())
+    }
+
+    /// Reads a mapping file with one `family = font[, font...]` entry per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path)?;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.insert_entry(line)?;
+        }
+        Ok(())
+    }
+
+    /// Resolves a CSS `font-family` list into the typst fallback list.
+    pub fn resolve(&self, font_family: &str) -> Vec<String> {
+        let mut fonts: Vec<String> = Vec::new();
+        for family in parse_font_families(font_family) {
+            let key = family.to_lowercase();
+            let mapped = match self.map.get(&key) {
+                Some(mapped) => mapped.clone(),
+                None if GENERIC_FAMILIES.contains(&key.as_str()) => Vec::new(),
+                None => vec![family],
+            };
+            for font in mapped {
+                if !fonts.contains(&font) {
+                    fonts.push(font);
+                }
+            }
+        }
+        fonts
+    }
+}
+
+/// Splits a CSS family list, unquoting quoted names and collapsing whitespace in
+/// unquoted ones.
+pub fn parse_font_families(s: &str) -> Vec<String> {
+    let mut families = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+        let mut family = String::new();
+        if first == '"' || first == '\'' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => family.extend(chars.next()),
+                    c if c == first => break,
+                    c => family.push(c),
+                }
+            }
+            // skip anything up to the next separator
+            while chars.next_if(|c| *c != ',').is_some() {}
+        } else {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| *c != ',') {
+                name.push(c);
+            }
+            family = name.split_whitespace().collect::<Vec<_>>().join(" ");
+        }
+        if !family.is_empty() {
+            families.push(family);
+        }
+    }
+    families
+}
+
+/// Formats a list of fonts as a typst array of strings.
+pub fn format_font_array(fonts: &[String]) -> String {
+    let fonts: Vec<String> = fonts.iter().map(|font| string_literal(font)).collect();
+    if fonts.len() == 1 {
+        // a single element array needs its trailing comma
+        format!("({},)", fonts[0])
+    } else {
+        format!("({})", fonts.join(", "))
+    }
+}
@@ -0,0 +1,409 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::Result;
+use quick_xml::events::BytesStart;
+use svgtypes::{SimplePathSegment, SimplifyingPathParser, Transform};
+
+use crate::{apply_transform, parse_size, parse_size_list};
+
+/// Line segments per curve when flattening
+const CURVE_STEPS: usize = 16;
+
+/// A path flattened into polylines, one per subpath
+#[derive(Debug, Clone, Default)]
+pub struct Polylines(pub Vec<Vec<(f64, f64)>>);
+
+impl Polylines {
+    /// Flattens path data, with its points mapped through `transform`.
+    pub fn from_path_data(d: &str, transform: &Transform) -> Result<Self> {
+        let mut lines: Vec<Vec<(f64, f64)>> = Vec::new();
+        let mut start = (0.0, 0.0);
+        let mut last = (0.0, 0.0);
+        for segment in SimplifyingPathParser::from(d) {
+            match segment? {
+                SimplePathSegment::MoveTo { x, y } => {
+                    start = (x, y);
+                    last = start;
+                    lines.push(vec![apply_transform(last, transform)]);
+                    continue;
+                }
+                SimplePathSegment::LineTo { x, y } => {
+                    last = (x, y);
+                }
+                SimplePathSegment::Quadratic { x1, y1, x, y } => {
+                    let p0 = last;
+                    let line = lines.last_mut().unwrap();
+                    for i in 1..CURVE_STEPS {
+                        let t = i as f64 / CURVE_STEPS as f64;
+                        let u = 1.0 - t;
+                        let point = (
+                            u * u * p0.0 + 2.0 * u * t * x1 + t * t * x,
+                            u * u * p0.1 + 2.0 * u * t * y1 + t * t * y,
+                        );
+                        line.push(apply_transform(point, transform));
+                    }
+                    last = (x, y);
+                }
+                SimplePathSegment::CurveTo {
+                    x1,
+                    y1,
+                    x2,
+                    y2,
+                    x,
+                    y,
+                } => {
+                    let p0 = last;
+                    let line = lines.last_mut().unwrap();
+                    for i in 1..CURVE_STEPS {
+                        let t = i as f64 / CURVE_STEPS as f64;
+                        let u = 1.0 - t;
+                        let point = (
+                            u * u * u * p0.0
+                                + 3.0 * u * u * t * x1
+                                + 3.0 * u * t * t * x2
+                                + t * t * t * x,
+                            u * u * u * p0.1
+                                + 3.0 * u * u * t * y1
+                                + 3.0 * u * t * t * y2
+                                + t * t * t * y,
+                        );
+                        line.push(apply_transform(point, transform));
+                    }
+                    last = (x, y);
+                }
+                SimplePathSegment::ClosePath => {
+                    last = start;
+                }
+            }
+            // the parser starts every subpath with a move
+            lines
+                .last_mut()
+                .unwrap()
+                .push(apply_transform(last, transform));
+        }
+        Ok(Polylines(lines))
+    }
+
+    /// Total length, without the moves between subpaths
+    pub fn length(&self) -> f64 {
+        self.0
+            .iter()
+            .flat_map(|line| line.windows(2))
+            .map(|w| distance(w[0], w[1]))
+            .sum()
+    }
+
+    /// The point at a distance along the lines and the direction of the segment
+    /// there in radians, `None` beyond either end.
+    pub fn point_at(&self, mut dist: f64) -> Option<((f64, f64), f64)> {
+        if dist < 0.0 {
+            return None;
+        }
+        for (a, b) in self
+            .0
+            .iter()
+            .flat_map(|line| line.windows(2))
+            .map(|w| (w[0], w[1]))
+        {
+            let len = distance(a, b);
+            if len > 0.0 && dist <= len {
+                let t = dist / len;
+                let point = (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+                return Some((point, (b.1 - a.1).atan2(b.0 - a.0)));
+            }
+            dist -= len;
+        }
+        None
+    }
+
+    /// The bounding box as x, y, width and height
+    pub fn bounds(&self) -> Option<[f64; 4]> {
+        let mut points = self.0.iter().flatten();
+        let first = points.next()?;
+        let (mut min, mut max) = (*first, *first);
+        for p in points {
+            min = (min.0.min(p.0), min.1.min(p.1));
+            max = (max.0.max(p.0), max.1.max(p.1));
+        }
+        Some([min.0, min.1, max.0 - min.0, max.1 - min.1])
+    }
+
+    /// The same lines, traversed from their end to their start
+    pub fn reversed(&self) -> Self {
+        Polylines(
+            self.0
+                .iter()
+                .rev()
+                .map(|line| line.iter().rev().copied().collect())
+                .collect(),
+        )
+    }
+}
+
+/// A piece of an outline, in output coordinates
+#[derive(Debug, Clone, Copy)]
+pub enum Segment {
+    Line((f64, f64), (f64, f64)),
+    /// Start, first and second control point and end
+    Cubic((f64, f64), (f64, f64), (f64, f64), (f64, f64)),
+}
+
+impl Segment {
+    pub fn start(&self) -> (f64, f64) {
+        match *self {
+            Segment::Line(a, _) | Segment::Cubic(a, ..) => a,
+        }
+    }
+
+    pub fn end(&self) -> (f64, f64) {
+        match *self {
+            Segment::Line(_, b) | Segment::Cubic(.., b) => b,
+        }
+    }
+
+    pub fn point_at(&self, t: f64) -> (f64, f64) {
+        match *self {
+            Segment::Line(a, b) => lerp(a, b, t),
+            Segment::Cubic(p0, p1, p2, p3) => {
+                let u = 1.0 - t;
+                (
+                    u * u * u * p0.0
+                        + 3.0 * u * u * t * p1.0
+                        + 3.0 * u * t * t * p2.0
+                        + t * t * t * p3.0,
+                    u * u * u * p0.1
+                        + 3.0 * u * u * t * p1.1
+                        + 3.0 * u * t * t * p2.1
+                        + t * t * t * p3.1,
+                )
+            }
+        }
+    }
+
+    /// Direction at the start in radians, `None` for a segment without length
+    pub fn start_angle(&self) -> Option<f64> {
+        let points = match *self {
+            Segment::Line(a, b) => vec![a, b],
+            Segment::Cubic(p0, p1, p2, p3) => vec![p0, p1, p2, p3],
+        };
+        let from = points[0];
+        let to = points[1..].iter().find(|p| **p != from)?;
+        Some((to.1 - from.1).atan2(to.0 - from.0))
+    }
+
+    /// Direction at the end in radians, `None` for a segment without length
+    pub fn end_angle(&self) -> Option<f64> {
+        let points = match *self {
+            Segment::Line(a, b) => vec![b, a],
+            Segment::Cubic(p0, p1, p2, p3) => vec![p3, p2, p1, p0],
+        };
+        let to = points[0];
+        let from = points[1..].iter().find(|p| **p != to)?;
+        Some((to.1 - from.1).atan2(to.0 - from.0))
+    }
+
+    /// The part between `t0` and `t1`
+    pub fn split(&self, t0: f64, t1: f64) -> Segment {
+        match *self {
+            Segment::Line(a, b) => Segment::Line(lerp(a, b, t0), lerp(a, b, t1)),
+            Segment::Cubic(..) => {
+                // cut off the end first, then the start of what's left
+                let t = if t1 > 0.0 { t0 / t1 } else { 0.0 };
+                self.subdivide(t1).0.subdivide(t).1
+            }
+        }
+    }
+
+    // de Casteljau, the control points of both halves of a cubic
+    fn subdivide(&self, t: f64) -> (Segment, Segment) {
+        let Segment::Cubic(p0, p1, p2, p3) = *self else {
+            unreachable!()
+        };
+        let a = lerp(p0, p1, t);
+        let b = lerp(p1, p2, t);
+        let c = lerp(p2, p3, t);
+        let d = lerp(a, b, t);
+        let e = lerp(b, c, t);
+        let f = lerp(d, e, t);
+        (Segment::Cubic(p0, a, d, f), Segment::Cubic(f, e, c, p3))
+    }
+
+    /// Points along the segment, without its start
+    pub fn flatten(&self) -> Vec<(f64, f64)> {
+        match *self {
+            Segment::Line(_, b) => vec![b],
+            Segment::Cubic(..) => (1..=CURVE_STEPS)
+                .map(|i| self.point_at(i as f64 / CURVE_STEPS as f64))
+                .collect(),
+        }
+    }
+}
+
+/// A subpath as segments, closed subpaths end where they start
+#[derive(Debug, Clone, Default)]
+pub struct Subpath {
+    pub segments: Vec<Segment>,
+    pub closed: bool,
+}
+
+impl Subpath {
+    /// The corners of the subpath when filled, with curves flattened
+    pub fn polygon(&self) -> Vec<(f64, f64)> {
+        let mut points: Vec<(f64, f64)> = self
+            .segments
+            .first()
+            .map(|s| s.start())
+            .into_iter()
+            .collect();
+        points.extend(self.segments.iter().flat_map(|s| s.flatten()));
+        points
+    }
+}
+
+/// Splits parsed path data into subpaths, with quadratic curves raised to cubics
+/// and all points mapped through `transform`.
+pub fn path_outline(segments: &[SimplePathSegment], transform: &Transform) -> Vec<Subpath> {
+    let mut subpaths: Vec<Subpath> = Vec::new();
+    let mut start = (0.0, 0.0);
+    let mut last = (0.0, 0.0);
+    for segment in segments {
+        let p = |x: f64, y: f64| apply_transform((x, y), transform);
+        let segment = match *segment {
+            SimplePathSegment::MoveTo { x, y } => {
+                start = p(x, y);
+                last = start;
+                subpaths.push(Subpath::default());
+                continue;
+            }
+            SimplePathSegment::LineTo { x, y } => Segment::Line(last, p(x, y)),
+            SimplePathSegment::Quadratic { x1, y1, x, y } => {
+                let (q, end) = (p(x1, y1), p(x, y));
+                Segment::Cubic(last, lerp(last, q, 2.0 / 3.0), lerp(end, q, 2.0 / 3.0), end)
+            }
+            SimplePathSegment::CurveTo {
+                x1,
+                y1,
+                x2,
+                y2,
+                x,
+                y,
+            } => Segment::Cubic(last, p(x1, y1), p(x2, y2), p(x, y)),
+            SimplePathSegment::ClosePath => {
+                if let Some(subpath) = subpaths.last_mut() {
+                    if last != start {
+                        subpath.segments.push(Segment::Line(last, start));
+                    }
+                    subpath.closed = true;
+                }
+                last = start;
+                continue;
+            }
+        };
+        last = segment.end();
+        if let Some(subpath) = subpaths.last_mut() {
+            subpath.segments.push(segment);
+        }
+    }
+    subpaths.retain(|s| !s.segments.is_empty());
+    subpaths
+}
+
+/// Cubic approximation of an ellipse, mapped through `transform`
+pub fn ellipse_segments(cx: f64, cy: f64, rx: f64, ry: f64, transform: &Transform) -> Vec<Segment> {
+    // control point distance for quarter arcs
+    const KAPPA: f64 = 0.552_284_75;
+    let quadrants = [(1.0, 0.0), (0.0, 1.0), (-1.0, 0.0), (0.0, -1.0), (1.0, 0.0)];
+    quadrants
+        .windows(2)
+        .map(|w| {
+            let (a, b) = (w[0], w[1]);
+            let point = |x: f64, y: f64| apply_transform((cx + rx * x, cy + ry * y), transform);
+            Segment::Cubic(
+                point(a.0, a.1),
+                point(a.0 + KAPPA * b.0, a.1 + KAPPA * b.1),
+                point(b.0 + KAPPA * a.0, b.1 + KAPPA * a.1),
+                point(b.0, b.1),
+            )
+        })
+        .collect()
+}
+
+/// Outline of a basic shape or path element as polylines, mapped through the
+/// element's own transform into the coordinate system of its parent.
+pub fn shape_polylines(element: &BytesStart) -> Result<Option<Polylines>> {
+    let transform = match element.try_get_attribute(b"transform")? {
+        Some(t) => Transform::from_str(&t.unescape_value()?)?,
+        None => Transform::default(),
+    };
+    let mut lengths = HashMap::new();
+    for attr in element.attributes() {
+        let attr = attr?;
+        let key = attr.key.as_ref();
+        if matches!(
+            key,
+            b"x" | b"y" | b"width" | b"height" | b"cx" | b"cy" | b"r" | b"rx" | b"ry"
+        ) {
+            lengths.insert(key.to_vec(), parse_size(&attr.unescape_value()?, 1.0)?);
+        }
+    }
+    let length = |key: &[u8]| lengths.get(key).copied().unwrap_or(0.0);
+    let ellipse = |rx: f64, ry: f64| {
+        let points = ellipse_segments(length(b"cx"), length(b"cy"), rx, ry, &transform)
+            .iter()
+            .flat_map(|s| s.flatten())
+            .collect::<Vec<_>>();
+        let mut line = vec![*points.last().unwrap()];
+        line.extend(points);
+        Polylines(vec![line])
+    };
+    Ok(match element.name().as_ref() {
+        b"rect" => {
+            let (x, y, w, h) = (
+                length(b"x"),
+                length(b"y"),
+                length(b"width"),
+                length(b"height"),
+            );
+            let corners = [(x, y), (x + w, y), (x + w, y + h), (x, y + h)];
+            Some(Polylines(vec![
+                corners
+                    .into_iter()
+                    .map(|p| apply_transform(p, &transform))
+                    .collect(),
+            ]))
+        }
+        b"circle" => Some(ellipse(length(b"r"), length(b"r"))),
+        b"ellipse" => Some(ellipse(length(b"rx"), length(b"ry"))),
+        b"polygon" | b"polyline" => match element.try_get_attribute(b"points")? {
+            Some(points) => {
+                let numbers = parse_size_list(&points.unescape_value()?, 1.0)?;
+                Some(Polylines(vec![
+                    numbers
+                        .chunks_exact(2)
+                        .map(|p| apply_transform((p[0], p[1]), &transform))
+                        .collect(),
+                ]))
+            }
+            None => None,
+        },
+        b"path" => match element.try_get_attribute(b"d")? {
+            Some(d) => Some(Polylines::from_path_data(&d.unescape_value()?, &transform)?),
+            None => None,
+        },
+        _ => None,
+    })
+}
+
+/// Bounds of a `rect` or `path` element as x, y, width and height, in the
+/// coordinate system of the element's parent.
+pub fn shape_bounds(element: &BytesStart) -> Result<Option<[f64; 4]>> {
+    Ok(shape_polylines(element)?.and_then(|lines| lines.bounds()))
+}
+
+fn lerp(a: (f64, f64), b: (f64, f64), t: f64) -> (f64, f64) {
+    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    (b.0 - a.0).hypot(b.1 - a.1)
+}
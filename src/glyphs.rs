@@ -0,0 +1,144 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use log::debug;
+use quick_xml::events::BytesStart;
+use svgtypes::Transform;
+
+use crate::{
+    SvgStyle,
+    text::{TextLayout, TextOptions, TextPositions, TextTransform, TextWriter},
+    transform_multiply,
+};
+
+/// Text that matplotlib drew as `use` references to glyph outlines, inside a group
+/// like `<g id="text_1">`. The original string is in a comment before the glyphs:
+///
+/// ```svg
+/// <g id="text_1">
+///  <!-- Hello -->
+///  <g style="fill: #ff0000" transform="translate(62 252) scale(0.1 -0.1)">
+///   <use xlink:href="#DejaVuSans-48"/>
+///   <use xlink:href="#DejaVuSans-65" x="75.195312"/>
+/// ```
+#[derive(Debug)]
+pub struct GlyphText {
+    /// Depth of the events stack inside the text group
+    pub depth: usize,
+    comment: Option<String>,
+    fill: Option<String>,
+    // transform of the glyph outlines and position of the first glyph
+    origin: Option<(Transform, f64, f64)>,
+    // the string from the glyph ids, for groups without a comment
+    chars: String,
+    bold: bool,
+    italic: bool,
+}
+
+impl GlyphText {
+    /// Whether an element is the group of a matplotlib text
+    pub fn starts(element: &BytesStart) -> Result<bool> {
+        Ok(element.name().as_ref() == b"g"
+            && element
+                .try_get_attribute(b"id")?
+                .is_some_and(|id| id.value.starts_with(b"text_")))
+    }
+
+    pub fn new(depth: usize) -> Self {
+        GlyphText {
+            depth,
+            comment: None,
+            fill: None,
+            origin: None,
+            chars: String::new(),
+            bold: false,
+            italic: false,
+        }
+    }
+
+    pub fn comment(&mut self, text: &str) {
+        if self.comment.is_none() {
+            self.comment = Some(text.trim().to_string());
+        }
+    }
+
+    /// Takes the color of the glyphs from a nested group.
+    pub fn group(&mut self, element: &BytesStart) -> Result<()> {
+        if let Some(style) = element.try_get_attribute(b"style")? {
+            let style = SvgStyle::from_str(&style.unescape_value()?)?;
+            if style.fill.is_some() {
+                self.fill = style.fill;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a `use` of a glyph outline, `transform` is that of its parent.
+    pub fn glyph(&mut self, element: &BytesStart, transform: &Transform) -> Result<()> {
+        let Some(href) = element
+            .try_get_attribute(b"xlink:href")?
+            .or(element.try_get_attribute(b"href")?)
+        else {
+            return Ok(());
+        };
+        let href = href.unescape_value()?;
+        // ids are the postscript name of the font and the hexadecimal code point
+        let Some((font, code)) = href.trim_start_matches('#').rsplit_once('-') else {
+            return Ok(());
+        };
+        let Some(ch) = u32::from_str_radix(code, 16).ok().and_then(char::from_u32) else {
+            debug!("Not a glyph reference: {}", href);
+            return Ok(());
+        };
+        if self.origin.is_none() {
+            let mut x = 0.0;
+            let mut y = 0.0;
+            for attr in element.attributes() {
+                let attr = attr?;
+                let value = attr.unescape_value()?;
+                match attr.key.as_ref() {
+                    b"x" => x += value.parse::<f64>()?,
+                    b"y" => y += value.parse::<f64>()?,
+                    b"transform" => {
+                        let t = Transform::from_str(&value)?;
+                        x += t.e;
+                        y += t.f;
+                    }
+                    _ => {}
+                }
+            }
+            self.origin = Some((*transform, x, y));
+            self.bold = font.contains("Bold");
+            self.italic = font.contains("Oblique") || font.contains("Italic");
+        }
+        self.chars.push(ch);
+        Ok(())
+    }
+
+    /// Writes the text as one label in place of the glyphs.
+    pub fn emit(self, font_scale: f64, options: &TextOptions, writer: &mut TextWriter) {
+        let Some((transform, x, y)) = self.origin else {
+            return;
+        };
+        // glyph outlines have their y axis pointing up and an em of 100 units
+        let transform =
+            transform_multiply(&transform, &Transform::new(1.0, 0.0, 0.0, -1.0, 0.0, 0.0));
+        let pt_per_px = font_scale * TextTransform::from_transform(&transform).font_scale();
+        let style = SvgStyle {
+            font_size: Some(100.0 * pt_per_px),
+            font_weight: self.bold.then_some(700),
+            font_style: self.italic.then(|| "italic".to_string()),
+            fill: self.fill,
+            ..Default::default()
+        };
+        let positions = TextPositions {
+            x: vec![x],
+            y: vec![-y],
+            ..Default::default()
+        };
+        let text = self.comment.unwrap_or(self.chars);
+        let mut layout = TextLayout::new(transform, font_scale);
+        layout.push_text(&text, &Some(style), &[&positions]);
+        writer.write(&layout, options);
+    }
+}
@@ -0,0 +1,417 @@
+use anyhow::Result;
+use log::debug;
+use quick_xml::{
+    Reader,
+    escape::resolve_predefined_entity,
+    events::{BytesStart, Event},
+};
+use svgtypes::Transform;
+
+use crate::{
+    SvgStyle,
+    fonts::format_font_array,
+    parse_size,
+    text::{TextOptions, TextTransform, format_text, gen_content},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Align {
+    Start,
+    Center,
+    End,
+}
+
+/// The box a label is placed in, from the `foreignObject` and the layout styles of
+/// its outermost element
+#[derive(Debug)]
+struct LabelBox {
+    x: f64,
+    y: f64,
+    width: Option<f64>,
+    height: Option<f64>,
+    horizontal: Align,
+    vertical: Align,
+    wrap: bool,
+}
+
+/// The typst content of an element being read
+#[derive(Debug, Default)]
+struct Frame {
+    // applied to the content at the element's end, `{}` stands for the content
+    wrappers: Vec<String>,
+    parts: Vec<String>,
+    text: String,
+    // a block element ended, the next content starts on a new line
+    line_break: bool,
+}
+
+impl Frame {
+    fn flush_text(&mut self, options: &TextOptions) {
+        let text = std::mem::take(&mut self.text);
+        // white space at the start of a line is not shown
+        if !text.trim().is_empty() || !(text.is_empty() || self.parts.is_empty() || self.line_break)
+        {
+            self.push(format_text(&text, options));
+        }
+    }
+
+    fn push(&mut self, part: String) {
+        if self.line_break {
+            self.line_break = false;
+            self.parts.push("linebreak()".to_string());
+        }
+        self.parts.push(part);
+    }
+
+    /// Appends character data, collapsing white space like html does.
+    fn push_text(&mut self, text: &str) {
+        for c in text.chars() {
+            if !c.is_whitespace() {
+                self.text.push(c);
+            } else if !self.text.ends_with(' ') {
+                self.text.push(' ');
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.parts.is_empty() && self.text.trim().is_empty()
+    }
+
+    fn finish(mut self, options: &TextOptions) -> Option<String> {
+        self.flush_text(options);
+        if self.parts.is_empty() {
+            return None;
+        }
+        let mut content = if self.parts.len() == 1 {
+            self.parts.pop().unwrap()
+        } else {
+            format!("({})", self.parts.join(" + "))
+        };
+        for wrapper in self.wrappers.iter().rev() {
+            content = wrapper.replace("{}", &content);
+        }
+        Some(content)
+    }
+}
+
+fn is_block(name: &[u8]) -> bool {
+    matches!(
+        name,
+        b"div"
+            | b"p"
+            | b"li"
+            | b"ul"
+            | b"ol"
+            | b"h1"
+            | b"h2"
+            | b"h3"
+            | b"h4"
+            | b"h5"
+            | b"h6"
+            | b"table"
+            | b"tr"
+            | b"blockquote"
+    )
+}
+
+/// Converts a CSS color into a typst color.
+fn css_color(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.starts_with('#') {
+        return Some(format!("rgb(\"{}\")", value));
+    }
+    if let Some(args) = value.strip_prefix("rgb(").or(value.strip_prefix("rgba(")) {
+        let args: Vec<&str> = args
+            .trim_end_matches(')')
+            .split(',')
+            .map(str::trim)
+            .collect();
+        return match args.as_slice() {
+            [r, g, b] => Some(format!("rgb({}, {}, {})", r, g, b)),
+            [r, g, b, a] => {
+                let alpha = a.parse::<f64>().ok()?;
+                Some(format!("rgb({}, {}, {}, {}%)", r, g, b, alpha * 100.0))
+            }
+            _ => None,
+        };
+    }
+    // names that css and typst share
+    match value {
+        "black" | "gray" | "silver" | "white" | "navy" | "blue" | "aqua" | "teal" | "purple"
+        | "fuchsia" | "maroon" | "red" | "orange" | "yellow" | "olive" | "green" | "lime" => {
+            Some(value.to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Reads the styles of an html element into text properties and layout properties
+/// of the label box.
+fn element_style(
+    element: &BytesStart,
+    pt_per_px: f64,
+    options: &TextOptions,
+    frame: &mut Frame,
+    label: Option<&mut LabelBox>,
+) -> Result<()> {
+    let mut style = SvgStyle::default();
+    let mut fill = None;
+    let mut declarations = Vec::new();
+    for attr in element.attributes() {
+        let attr = attr?;
+        let value = attr.unescape_value()?.to_string();
+        match attr.key.local_name().as_ref() {
+            b"style" => {
+                for declaration in value.split(';') {
+                    if let Some((key, value)) = declaration.split_once(':') {
+                        declarations.push((key.trim().to_string(), value.trim().to_string()));
+                    }
+                }
+            }
+            // the attributes of <font>
+            b"color" => fill = css_color(&value),
+            b"face" => style.font_family = Some(value),
+            _ => {}
+        }
+    }
+    let mut label = label;
+    for (key, value) in &declarations {
+        match key.as_str() {
+            "color" => fill = css_color(value),
+            "margin-left" | "padding-top" | "width" | "height" | "align-items"
+            | "justify-content" | "white-space"
+                if label.is_some() =>
+            {
+                let label = label.as_deref_mut().unwrap();
+                let align = |v: &str| match v.trim_start_matches("unsafe ").trim() {
+                    "center" => Align::Center,
+                    "flex-end" | "end" | "right" => Align::End,
+                    _ => Align::Start,
+                };
+                let length = || parse_size(value, 1.0).ok();
+                match key.as_str() {
+                    "margin-left" => label.x += length().unwrap_or(0.0),
+                    "padding-top" => label.y += length().unwrap_or(0.0),
+                    "width" => label.width = length().or(label.width),
+                    "height" => label.height = length().or(label.height),
+                    "align-items" => label.vertical = align(value),
+                    "justify-content" => label.horizontal = align(value),
+                    _ => label.wrap = !value.starts_with("nowrap") && value != "pre",
+                }
+            }
+            _ => match style.set_property(key, value, pt_per_px, 1.0) {
+                Ok(true) => {}
+                Ok(false) => debug!("Unprocessed html style {}: {}", key, value),
+                Err(e) => debug!("Invalid html style {}: {}: {}", key, value, e),
+            },
+        }
+    }
+    if let Some(align) = &style.text_align
+        && is_block(element.local_name().as_ref())
+    {
+        match align.as_str() {
+            "center" => frame.wrappers.push("align(center, {})".to_string()),
+            "right" | "end" => frame.wrappers.push("align(right, {})".to_string()),
+            _ => {}
+        }
+    }
+    let mut args = String::new();
+    if let Some(size) = style.font_size
+        && size > 0.0
+    {
+        args.push_str(&format!("size: {:.3}pt, ", size));
+    }
+    if let Some(family) = &style.font_family {
+        let fonts = options.font_map.resolve(family);
+        if !fonts.is_empty() {
+            args.push_str(&format!("font: {}, ", format_font_array(&fonts)));
+        }
+    }
+    if let Some(weight) = style.font_weight {
+        args.push_str(&format!("weight: {}, ", weight));
+    }
+    if let Some(font_style) = &style.font_style {
+        args.push_str(&format!("style: \"{}\", ", font_style));
+    }
+    if let Some(fill) = fill {
+        args.push_str(&format!("fill: {}, ", fill));
+    }
+    if !args.is_empty() {
+        frame.wrappers.push(format!("text({}{{}})", args));
+    }
+    if let Some(decoration) = style.text_decoration {
+        if decoration.underline {
+            frame.wrappers.push("underline({})".to_string());
+        }
+        if decoration.line_through {
+            frame.wrappers.push("strike({})".to_string());
+        }
+        if decoration.overline {
+            frame.wrappers.push("overline({})".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Reads a `foreignObject` and emits its html as one `content` in the object's box.
+/// Returns false if there is no text in it.
+pub fn read_foreign_object(
+    reader: &mut Reader<&[u8]>,
+    element: &BytesStart,
+    transform: &Transform,
+    font_scale: f64,
+    options: &TextOptions,
+) -> Result<bool> {
+    let mut label = LabelBox {
+        x: 0.0,
+        y: 0.0,
+        width: None,
+        height: None,
+        horizontal: Align::Start,
+        vertical: Align::Start,
+        wrap: true,
+    };
+    for attr in element.attributes() {
+        let attr = attr?;
+        if !matches!(attr.key.as_ref(), b"x" | b"y" | b"width" | b"height") {
+            continue;
+        }
+        let value = attr.unescape_value()?;
+        // percentages of the viewport are left to the html's own layout
+        let length = if value.ends_with('%') {
+            None
+        } else {
+            Some(parse_size(&value, 1.0)?)
+        };
+        match attr.key.as_ref() {
+            b"x" => label.x = length.unwrap_or(0.0),
+            b"y" => label.y = length.unwrap_or(0.0),
+            b"width" => label.width = length,
+            b"height" => label.height = length,
+            _ => {}
+        }
+    }
+    let pt_per_px = font_scale * TextTransform::from_transform(transform).font_scale();
+    let mut stack = vec![Frame::default()];
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                let name = element.local_name();
+                let parent = stack.last_mut().unwrap();
+                parent.flush_text(options);
+                let mut frame = Frame::default();
+                if is_block(name.as_ref()) && !parent.is_empty() {
+                    parent.line_break = true;
+                }
+                match name.as_ref() {
+                    b"b" | b"strong" => frame.wrappers.push("strong({})".to_string()),
+                    b"i" | b"em" => frame.wrappers.push("emph({})".to_string()),
+                    b"u" => frame.wrappers.push("underline({})".to_string()),
+                    b"s" | b"strike" | b"del" => frame.wrappers.push("strike({})".to_string()),
+                    b"sub" => frame.wrappers.push("sub({})".to_string()),
+                    b"sup" => frame.wrappers.push("super({})".to_string()),
+                    b"br" => parent.push("linebreak()".to_string()),
+                    _ => {}
+                }
+                let outermost = stack.len() == 1;
+                element_style(
+                    &element,
+                    pt_per_px,
+                    options,
+                    &mut frame,
+                    outermost.then_some(&mut label),
+                )?;
+                stack.push(frame);
+            }
+            Event::Empty(element) if element.local_name().as_ref() == b"br" => {
+                let frame = stack.last_mut().unwrap();
+                frame.flush_text(options);
+                frame.push("linebreak()".to_string());
+            }
+            Event::End(end) => {
+                let Some(frame) = stack.pop() else {
+                    break;
+                };
+                let Some(parent) = stack.last_mut() else {
+                    // the end of the foreignObject
+                    stack.push(frame);
+                    break;
+                };
+                if let Some(content) = frame.finish(options) {
+                    parent.flush_text(options);
+                    parent.push(content);
+                }
+                if is_block(end.local_name().as_ref()) {
+                    parent.line_break = !parent.is_empty();
+                }
+            }
+            Event::Text(text) => {
+                stack.last_mut().unwrap().push_text(&text.xml_content()?);
+            }
+            Event::CData(text) => {
+                stack.last_mut().unwrap().push_text(&text.xml_content()?);
+            }
+            Event::GeneralRef(reference) => {
+                let text = match reference.resolve_char_ref()? {
+                    Some(ch) => ch.to_string(),
+                    None => {
+                        let name = reference.decode()?;
+                        match name.as_ref() {
+                            "nbsp" => "\u{a0}".to_string(),
+                            name => resolve_predefined_entity(name)
+                                .map_or_else(|| format!("&{};", name), str::to_string),
+                        }
+                    }
+                };
+                stack.last_mut().unwrap().text.push_str(&text);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    let Some(body) = stack.pop().and_then(|frame| frame.finish(options)) else {
+        return Ok(false);
+    };
+    let offset = |align: Align, size: Option<f64>| match align {
+        Align::Start => 0.0,
+        Align::Center => size.unwrap_or(0.0) / 2.0,
+        Align::End => size.unwrap_or(0.0),
+    };
+    let vertical = match label.vertical {
+        Align::Start => "north",
+        Align::Center => "",
+        Align::End => "south",
+    };
+    let y = label.y + offset(label.vertical, label.height);
+    if let Some(width) = label.width.filter(|_| label.wrap) {
+        let anchor = if vertical.is_empty() {
+            "west".to_string()
+        } else {
+            format!("{}-west", vertical)
+        };
+        let body = format!("block(width: {:.3}pt, {})", width * pt_per_px, body);
+        gen_content(
+            (label.x, y),
+            transform,
+            &anchor,
+            body,
+            options.backend.emitter(),
+        );
+    } else {
+        let horizontal = match label.horizontal {
+            Align::Start => "west",
+            Align::Center => "",
+            Align::End => "east",
+        };
+        let anchor = match (vertical, horizontal) {
+            ("", "") => "center".to_string(),
+            ("", h) => h.to_string(),
+            (v, "") => v.to_string(),
+            (v, h) => format!("{}-{}", v, h),
+        };
+        let x = label.x + offset(label.horizontal, label.width);
+        gen_content((x, y), transform, &anchor, body, options.backend.emitter());
+    }
+    Ok(true)
+}
@@ -0,0 +1,374 @@
+use std::{cell::Cell, fs, path::PathBuf, str::FromStr};
+
+use anyhow::{Result, bail};
+use log::{debug, warn};
+use quick_xml::events::BytesStart;
+use svgtypes::Transform;
+
+use crate::{
+    apply_transform,
+    clip::intersect,
+    emit::Emitter,
+    parse_size,
+    text::{TextTransform, gen_content},
+    transform_multiply,
+    typst::string_literal,
+};
+
+/// Where images are read from and written to
+#[derive(Debug, Default)]
+pub struct ImageOptions {
+    /// Directory that decoded `data:` images are written to, they are embedded as
+    /// bytes without one
+    pub dir: Option<PathBuf>,
+    /// Directory that relative image paths are resolved against
+    pub base: Option<PathBuf>,
+    // images written so far, for file names
+    written: Cell<usize>,
+}
+
+impl ImageOptions {
+    pub fn new(dir: Option<PathBuf>, base: Option<PathBuf>) -> Self {
+        ImageOptions {
+            dir,
+            base,
+            written: Cell::new(0),
+        }
+    }
+}
+
+/// `preserveAspectRatio`, as fractions of the free space before the image
+#[derive(Debug, Clone, Copy)]
+struct AspectRatio {
+    /// `None` stretches the image to the viewport
+    align: Option<(f64, f64)>,
+    slice: bool,
+}
+
+impl AspectRatio {
+    fn parse(value: &str) -> Self {
+        let mut parts = value.split_whitespace();
+        let align = parts.next().unwrap_or("xMidYMid");
+        let slice = parts.next() == Some("slice");
+        if align == "none" {
+            return AspectRatio { align: None, slice };
+        }
+        let fraction = |s: &str| match s {
+            "Min" => 0.0,
+            "Max" => 1.0,
+            _ => 0.5,
+        };
+        let align = match (align.get(1..4), align.get(5..8)) {
+            (Some(x), Some(y)) => (fraction(x), fraction(y)),
+            _ => (0.5, 0.5),
+        };
+        AspectRatio {
+            align: Some(align),
+            slice,
+        }
+    }
+}
+
+/// Writes an `image` element as a Typst image, `transform` is that of its parent.
+/// `clip` is the rectangle it's cut to in output coordinates, as lower left and
+/// upper right corner.
+pub fn emit_image(
+    element: &BytesStart,
+    transform: &Transform,
+    clip: Option<[f64; 4]>,
+    options: &ImageOptions,
+    emitter: &dyn Emitter,
+) -> Result<()> {
+    let mut transform = *transform;
+    let (mut x, mut y) = (0.0, 0.0);
+    let (mut width, mut height) = (None, None);
+    let mut href = None;
+    let mut aspect = AspectRatio::parse("");
+    let mut relative = false;
+    for attr in element.attributes() {
+        let attr = attr?;
+        let value = attr.unescape_value()?;
+        match attr.key.as_ref() {
+            b"x" | b"y" | b"width" | b"height" if value.ends_with('%') => relative = true,
+            b"x" => x = parse_size(&value, 1.0)?,
+            b"y" => y = parse_size(&value, 1.0)?,
+            b"width" if value != "auto" => width = Some(parse_size(&value, 1.0)?),
+            b"height" if value != "auto" => height = Some(parse_size(&value, 1.0)?),
+            b"href" | b"xlink:href" => href = Some(value.to_string()),
+            b"preserveAspectRatio" => aspect = AspectRatio::parse(&value),
+            b"transform" => {
+                transform = transform_multiply(&transform, &Transform::from_str(&value)?)
+            }
+            key => debug!(
+                "Unprocessed attributes for <image> {}",
+                str::from_utf8(key)?
+            ),
+        }
+    }
+    let Some(href) = href else {
+        return Ok(());
+    };
+    // the viewport percentages refer to isn't known
+    if relative {
+        warn!(
+            "Image {} positioned relative to the viewport, skipped",
+            shorten(&href)
+        );
+        return Ok(());
+    }
+    let Some((source, data)) = image_source(&href, options)? else {
+        return Ok(());
+    };
+    let intrinsic = data.as_deref().and_then(image_size);
+    // a missing size is that of the image itself
+    let (width, height) = match (width, height, intrinsic) {
+        (Some(w), Some(h), _) => (w, h),
+        (Some(w), None, Some((iw, ih))) => (w, w * ih / iw),
+        (None, Some(h), Some((iw, ih))) => (h * iw / ih, h),
+        (None, None, Some(size)) => size,
+        _ => {
+            warn!("Image {} without a size, skipped", shorten(&href));
+            return Ok(());
+        }
+    };
+    if width <= 0.0 || height <= 0.0 {
+        return Ok(());
+    }
+    // sized like the shapes around it, output units are centimeters
+    let cm_per_px = TextTransform::from_transform(&transform).font_scale();
+    let size = |w: f64, h: f64| {
+        format!(
+            "width: {:.3}cm, height: {:.3}cm",
+            w * cm_per_px,
+            h * cm_per_px
+        )
+    };
+    let mut body = match (aspect.align, intrinsic) {
+        (None, _) => format!(
+            "image({}, {}, fit: \"stretch\")",
+            source,
+            size(width, height)
+        ),
+        // place the image in the viewport the way svg does, cut off where it overflows
+        (Some((ax, ay)), Some((iw, ih))) => {
+            let scale = if aspect.slice {
+                (width / iw).max(height / ih)
+            } else {
+                (width / iw).min(height / ih)
+            };
+            let (w, h) = (iw * scale, ih * scale);
+            let image = format!("image({}, {})", source, size(w, h));
+            if (w - width).abs() < 1e-6 && (h - height).abs() < 1e-6 {
+                image
+            } else {
+                format!(
+                    "box({}, clip: {}, place(dx: {:.3}cm, dy: {:.3}cm, {}))",
+                    size(width, height),
+                    aspect.slice,
+                    // adding zero turns -0 into 0
+                    (width - w) * ax * cm_per_px + 0.0,
+                    (height - h) * ay * cm_per_px + 0.0,
+                    image
+                )
+            }
+        }
+        // without the image's size typst aligns it in the middle
+        (Some(_), None) => format!(
+            "image({}, {}, fit: \"{}\")",
+            source,
+            size(width, height),
+            if aspect.slice { "cover" } else { "contain" }
+        ),
+    };
+    let mut position = (x, y);
+    if let Some([cx0, cy0, cx1, cy1]) = clip {
+        // only upright images at the same scale along both axes are cut, their
+        // content is then in the same units as the output
+        if transform.b != 0.0
+            || transform.c != 0.0
+            || transform.a <= 0.0
+            || (transform.a + transform.d).abs() > 1e-9
+        {
+            warn!("Clip path on a rotated or mirrored image, drawn unclipped");
+        } else {
+            let (ix0, iy1) = apply_transform((x, y), &transform);
+            let (ix1, iy0) = apply_transform((x + width, y + height), &transform);
+            let Some([x0, y0, x1, y1]) = intersect([ix0, iy0, ix1, iy1], [cx0, cy0, cx1, cy1])
+            else {
+                return Ok(());
+            };
+            if [x0, y0, x1, y1] != [ix0, iy0, ix1, iy1] {
+                body = format!(
+                    "box(width: {:.3}cm, height: {:.3}cm, clip: true, place(dx: {:.3}cm, dy: {:.3}cm, {}))",
+                    x1 - x0,
+                    y1 - y0,
+                    ix0 - x0 + 0.0,
+                    y1 - iy1 + 0.0,
+                    body
+                );
+                position = (
+                    (x0 - transform.e) / transform.a,
+                    (y1 - transform.f) / transform.d,
+                );
+            }
+        }
+    }
+    gen_content(position, &transform, "north-west", body, emitter);
+    Ok(())
+}
+
+/// Bounds of an `image` element in its user space, if it has a size that doesn't
+/// depend on the viewport
+pub fn image_bounds(element: &BytesStart) -> Result<Option<[f64; 4]>> {
+    let size = |name: &[u8]| -> Result<Option<f64>> {
+        Ok(match element.try_get_attribute(name)? {
+            Some(a) if a.unescape_value()? != "auto" => {
+                Some(parse_size(&a.unescape_value()?, 1.0)?)
+            }
+            _ => None,
+        })
+    };
+    let relative = [b"x".as_slice(), b"y", b"width", b"height"]
+        .iter()
+        .any(|name| {
+            element
+                .try_get_attribute(*name)
+                .ok()
+                .flatten()
+                .is_some_and(|a| a.value.ends_with(b"%"))
+        });
+    if relative {
+        return Ok(None);
+    }
+    let (Some(width), Some(height)) = (size(b"width")?, size(b"height")?) else {
+        return Ok(None);
+    };
+    let (x, y) = (size(b"x")?.unwrap_or(0.0), size(b"y")?.unwrap_or(0.0));
+    Ok(Some([x, y, width, height]))
+}
+
+/// The Typst source of an image and its data where available
+fn image_source(href: &str, options: &ImageOptions) -> Result<Option<(String, Option<Vec<u8>>)>> {
+    if let Some(uri) = href.strip_prefix("data:") {
+        let Some((header, payload)) = uri.split_once(',') else {
+            bail!("malformed data URI {}", shorten(href));
+        };
+        let data = if header.ends_with(";base64") {
+            decode_base64(payload)?
+        } else {
+            percent_decode(payload)
+        };
+        let mime = header.split(';').next().unwrap_or("");
+        let Some(dir) = &options.dir else {
+            let bytes: Vec<String> = data.iter().map(u8::to_string).collect();
+            return Ok(Some((format!("bytes(({},))", bytes.join(",")), Some(data))));
+        };
+        let extension = match mime {
+            "image/jpeg" | "image/jpg" => "jpg",
+            "image/gif" => "gif",
+            "image/svg+xml" => "svg",
+            "image/webp" => "webp",
+            _ => "png",
+        };
+        let count = options.written.get() + 1;
+        options.written.set(count);
+        let path = dir.join(format!("image-{}.{}", count, extension));
+        fs::create_dir_all(dir)?;
+        fs::write(&path, &data)?;
+        debug!("wrote {}", path.display());
+        return Ok(Some((string_literal(&path.to_string_lossy()), Some(data))));
+    }
+    if href.contains("://") && !href.starts_with("file://") {
+        warn!("Remote image {} can't be embedded, skipped", href);
+        return Ok(None);
+    }
+    let path = PathBuf::from(href.trim_start_matches("file://"));
+    let path = match &options.base {
+        Some(base) if path.is_relative() => base.join(path),
+        _ => path,
+    };
+    // the size is read from the file where it can be found from here
+    let data = fs::read(&path).ok();
+    Ok(Some((string_literal(&path.to_string_lossy()), data)))
+}
+
+/// Width and height of a PNG, JPEG or GIF in pixels
+fn image_size(data: &[u8]) -> Option<(f64, f64)> {
+    let be16 = |i: usize| Some(u16::from_be_bytes([*data.get(i)?, *data.get(i + 1)?]) as f64);
+    if data.starts_with(b"\x89PNG") {
+        let be32 = |i: usize| Some(u32::from_be_bytes(data.get(i..i + 4)?.try_into().ok()?) as f64);
+        return Some((be32(16)?, be32(20)?));
+    }
+    if data.starts_with(b"GIF8") {
+        let le16 = |i: usize| Some(u16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]) as f64);
+        return Some((le16(6)?, le16(8)?));
+    }
+    if data.starts_with(&[0xff, 0xd8]) {
+        // walk the segments up to a start of frame
+        let mut i = 2;
+        while i + 9 < data.len() {
+            if data[i] != 0xff {
+                return None;
+            }
+            let marker = data[i + 1];
+            if matches!(marker, 0xc0..=0xcf) && !matches!(marker, 0xc4 | 0xc8 | 0xcc) {
+                return Some((be16(i + 7)?, be16(i + 5)?));
+            }
+            i += 2 + be16(i + 2)? as usize;
+        }
+    }
+    None
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            c if c.is_ascii_whitespace() => continue,
+            c => bail!("invalid character {:?} in base64 data", c as char),
+        };
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(data)
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut data = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = text
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        {
+            data.push(byte);
+            i += 3;
+        } else {
+            data.push(bytes[i]);
+            i += 1;
+        }
+    }
+    data
+}
+
+/// A reference short enough for a log message
+fn shorten(href: &str) -> &str {
+    match href.char_indices().nth(60) {
+        Some((i, _)) => &href[..i],
+        None => href,
+    }
+}
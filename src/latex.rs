@@ -0,0 +1,575 @@
+use anyhow::{Result, anyhow, bail};
+
+use crate::typst::string_literal;
+
+/// A part of a text label, either plain text or LaTeX math with its delimiters
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Math { source: &'a str, latex: &'a str },
+}
+
+/// Splits text at `$...$`, `$$...$$`, `\(...\)` and `\[...\]` math.
+///
+/// Like pandoc, a single `$` only opens math when followed by a non-space and only
+/// closes it when preceded by a non-space and not followed by a digit, so that
+/// prices like `$5 and $10` stay text.
+pub fn split_math(text: &str) -> Vec<Segment<'_>> {
+    let mut segments = Vec::new();
+    let mut text_start = 0;
+    let mut i = 0;
+    while i < text.len() {
+        let rest = &text[i..];
+        let math = if rest.starts_with("\\(") {
+            find_math(text, i, "\\(", "\\)")
+        } else if rest.starts_with("\\[") {
+            find_math(text, i, "\\[", "\\]")
+        } else if rest.starts_with("$$") {
+            find_math(text, i, "$$", "$$")
+        } else if rest.starts_with('$') && !text[..i].ends_with('\\') {
+            find_dollar_math(text, i)
+        } else {
+            None
+        };
+        match math {
+            Some((end, latex)) => {
+                if text_start < i {
+                    segments.push(Segment::Text(&text[text_start..i]));
+                }
+                segments.push(Segment::Math {
+                    source: &text[i..end],
+                    latex,
+                });
+                i = end;
+                text_start = end;
+            }
+            None => i += rest.chars().next().map_or(1, char::len_utf8),
+        }
+    }
+    if text_start < text.len() {
+        segments.push(Segment::Text(&text[text_start..]));
+    }
+    segments
+}
+
+fn find_math<'a>(text: &'a str, start: usize, open: &str, close: &str) -> Option<(usize, &'a str)> {
+    let inner_start = start + open.len();
+    let inner_len = text[inner_start..].find(close)?;
+    let latex = &text[inner_start..inner_start + inner_len];
+    (!latex.trim().is_empty()).then_some((inner_start + inner_len + close.len(), latex))
+}
+
+fn find_dollar_math(text: &str, start: usize) -> Option<(usize, &str)> {
+    let inner_start = start + 1;
+    if text[inner_start..].starts_with(char::is_whitespace) {
+        return None;
+    }
+    let mut escaped = false;
+    for (offset, c) in text[inner_start..].char_indices() {
+        let pos = inner_start + offset;
+        match c {
+            '\\' => escaped = !escaped,
+            '$' if !escaped => {
+                let latex = &text[inner_start..pos];
+                if latex.is_empty()
+                    || latex.ends_with(char::is_whitespace)
+                    || text[pos + 1..].starts_with(|c: char| c.is_ascii_digit())
+                {
+                    return None;
+                }
+                return Some((pos + 1, latex));
+            }
+            _ => escaped = false,
+        }
+    }
+    None
+}
+
+/// Converts a practical subset of LaTeX math into typst math, without the `$`
+/// delimiters. Fails on commands that have no known translation.
+pub fn to_typst_math(latex: &str) -> Result<String> {
+    let mut parser = Parser {
+        chars: latex.chars().collect(),
+        pos: 0,
+    };
+    let atoms = parser.parse_sequence()?;
+    if parser.pos < parser.chars.len() {
+        bail!("unbalanced braces in {}", latex);
+    }
+    Ok(join_atoms(&atoms))
+}
+
+fn join_atoms(atoms: &[String]) -> String {
+    let mut r = String::new();
+    for atom in atoms {
+        // primes attach to the preceding atom
+        if !r.is_empty() && !atom.starts_with('\'') {
+            r.push(' ');
+        }
+        r.push_str(atom);
+    }
+    r
+}
+
+/// Whether an atom can be a script or function argument without parentheses
+fn is_simple(atom: &str) -> bool {
+    atom.chars().count() == 1 || atom.chars().all(|c| c.is_alphanumeric() || c == '.')
+}
+
+fn greek(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "alpha" => "alpha",
+        "beta" => "beta",
+        "gamma" => "gamma",
+        "delta" => "delta",
+        // latex' epsilon is the lunate form
+        "epsilon" => "epsilon.alt",
+        "varepsilon" => "epsilon",
+        "zeta" => "zeta",
+        "eta" => "eta",
+        "theta" => "theta",
+        "vartheta" => "theta.alt",
+        "iota" => "iota",
+        "kappa" => "kappa",
+        "varkappa" => "kappa.alt",
+        "lambda" => "lambda",
+        "mu" => "mu",
+        "nu" => "nu",
+        "xi" => "xi",
+        "omicron" => "omicron",
+        "pi" => "pi",
+        "varpi" => "pi.alt",
+        "rho" => "rho",
+        "varrho" => "rho.alt",
+        "sigma" => "sigma",
+        "varsigma" => "sigma.alt",
+        "tau" => "tau",
+        "upsilon" => "upsilon",
+        "phi" => "phi.alt",
+        "varphi" => "phi",
+        "chi" => "chi",
+        "psi" => "psi",
+        "omega" => "omega",
+        "Gamma" => "Gamma",
+        "Delta" => "Delta",
+        "Theta" => "Theta",
+        "Lambda" => "Lambda",
+        "Xi" => "Xi",
+        "Pi" => "Pi",
+        "Sigma" => "Sigma",
+        "Upsilon" => "Upsilon",
+        "Phi" => "Phi",
+        "Psi" => "Psi",
+        "Omega" => "Omega",
+        _ => return None,
+    })
+}
+
+fn symbol(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "times" => "times",
+        "cdot" => "dot",
+        "ast" => "ast",
+        "star" => "star",
+        "pm" => "plus.minus",
+        "mp" => "minus.plus",
+        "div" => "div",
+        "le" | "leq" => "<=",
+        "ge" | "geq" => ">=",
+        "ll" => "<<",
+        "gg" => ">>",
+        "ne" | "neq" => "!=",
+        "approx" => "approx",
+        "equiv" => "equiv",
+        "sim" => "tilde.op",
+        "simeq" => "tilde.eq",
+        "cong" => "tilde.equiv",
+        "propto" => "prop",
+        "infty" => "infinity",
+        "partial" => "diff",
+        "nabla" => "nabla",
+        "sum" => "sum",
+        "prod" => "product",
+        "coprod" => "product.co",
+        "int" => "integral",
+        "iint" => "integral.double",
+        "iiint" => "integral.triple",
+        "oint" => "integral.cont",
+        "to" | "rightarrow" => "->",
+        "leftarrow" | "gets" => "<-",
+        "leftrightarrow" => "<->",
+        "Rightarrow" | "implies" => "=>",
+        "Leftarrow" => "arrow.l.double",
+        "Leftrightarrow" | "iff" => "<=>",
+        "mapsto" => "|->",
+        "uparrow" => "arrow.t",
+        "downarrow" => "arrow.b",
+        "in" => "in",
+        "notin" => "in.not",
+        "ni" => "in.rev",
+        "subset" => "subset",
+        "subseteq" => "subset.eq",
+        "supset" => "supset",
+        "supseteq" => "supset.eq",
+        "cup" => "union",
+        "cap" => "sect",
+        "setminus" => "without",
+        "emptyset" | "varnothing" => "emptyset",
+        "forall" => "forall",
+        "exists" => "exists",
+        "neg" | "lnot" => "not",
+        "land" | "wedge" => "and",
+        "lor" | "vee" => "or",
+        "oplus" => "plus.circle",
+        "otimes" => "times.circle",
+        "perp" => "perp",
+        "parallel" => "parallel",
+        "angle" => "angle",
+        "ldots" | "dots" => "dots.h",
+        "cdots" => "dots.c",
+        "vdots" => "dots.v",
+        "ddots" => "dots.down",
+        "circ" => "circle.small",
+        "bullet" => "bullet",
+        "degree" => "degree",
+        "prime" => "prime",
+        "ell" => "ell",
+        "hbar" => "planck.reduce",
+        "Re" => "Re",
+        "Im" => "Im",
+        "aleph" => "aleph",
+        "langle" => "angle.l",
+        "rangle" => "angle.r",
+        "lfloor" => "floor.l",
+        "rfloor" => "floor.r",
+        "lceil" => "ceil.l",
+        "rceil" => "ceil.r",
+        "vert" | "mid" => "|",
+        "Vert" | "|" => "bar.v.double",
+        "{" | "lbrace" => "{",
+        "}" | "rbrace" => "}",
+        "%" => "%",
+        "$" => "\\$",
+        "#" => "\\#",
+        "&" => "&",
+        "_" => "\\_",
+        "," => "thin",
+        ":" | ">" => "med",
+        ";" => "thick",
+        " " => "space",
+        "quad" => "quad",
+        "qquad" => "wide",
+        "\\" => "\\",
+        _ => return None,
+    })
+}
+
+/// Operators typst typesets upright under the same name
+const OPERATORS: [&str; 26] = [
+    "sin", "cos", "tan", "cot", "sec", "csc", "arcsin", "arccos", "arctan", "sinh", "cosh", "tanh",
+    "log", "ln", "lg", "exp", "lim", "liminf", "limsup", "max", "min", "sup", "inf", "det", "arg",
+    "deg",
+];
+
+/// Commands with a single argument that map to a typst function
+fn function(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "mathbb" => "bb",
+        "mathbf" | "boldsymbol" | "bm" => "bold",
+        "mathit" => "italic",
+        "mathcal" => "cal",
+        "mathsf" => "sans",
+        "mathtt" => "mono",
+        "mathfrak" => "frak",
+        "sqrt" => "sqrt",
+        "hat" | "widehat" => "hat",
+        "tilde" | "widetilde" => "tilde",
+        "bar" => "macron",
+        "vec" => "arrow",
+        "dot" => "dot",
+        "ddot" => "dot.double",
+        "acute" => "acute",
+        "grave" => "grave",
+        "breve" => "breve",
+        "check" => "caron",
+        "overline" => "overline",
+        "underline" => "underline",
+        "overbrace" => "overbrace",
+        "underbrace" => "underbrace",
+        "cancel" => "cancel",
+        _ => return None,
+    })
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Parses atoms up to the end or a closing brace, which is left unconsumed.
+    fn parse_sequence(&mut self) -> Result<Vec<String>> {
+        let mut atoms: Vec<String> = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some('}') => return Ok(atoms),
+                Some(c @ ('^' | '_')) => {
+                    self.pos += 1;
+                    let script = self.parse_argument()?;
+                    let base = atoms.pop().unwrap_or_else(|| "\"\"".to_string());
+                    atoms.push(format!("{}{}{}", base, c, wrap(&script)));
+                }
+                Some('{') => {
+                    self.pos += 1;
+                    // typst has no invisible grouping, scripts on a group attach to its
+                    // last atom instead
+                    atoms.extend(self.parse_sequence()?);
+                    self.expect('}')?;
+                }
+                Some(_) => {
+                    if let Some(atom) = self.parse_atom()? {
+                        atoms.push(atom);
+                    }
+                }
+            }
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        if self.peek() != Some(c) {
+            bail!("expected {} at {}", c, self.pos);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Parses a group or a single atom, as used for arguments and scripts.
+    fn parse_argument(&mut self) -> Result<Vec<String>> {
+        self.skip_whitespace();
+        if self.peek() == Some('{') {
+            self.pos += 1;
+            let atoms = self.parse_sequence()?;
+            self.expect('}')?;
+            Ok(atoms)
+        } else {
+            match self.parse_atom()? {
+                Some(atom) => Ok(vec![atom]),
+                None => bail!("missing argument at {}", self.pos),
+            }
+        }
+    }
+
+    /// Parses an argument for a typst function call.
+    fn parse_call_argument(&mut self) -> Result<String> {
+        Ok(join_atoms(&self.parse_argument()?))
+    }
+
+    /// Reads a brace-delimited argument verbatim, for text commands.
+    fn parse_verbatim(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        self.expect('{')?;
+        let mut depth = 0;
+        let mut r = String::new();
+        while let Some(c) = self.peek() {
+            self.pos += 1;
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => return Ok(r),
+                '}' => depth -= 1,
+                _ => {}
+            }
+            r.push(c);
+        }
+        bail!("unterminated argument")
+    }
+
+    /// Parses one character, number or command. Returns `None` for commands that
+    /// produce nothing, like sizing commands.
+    fn parse_atom(&mut self) -> Result<Option<String>> {
+        let Some(c) = self.peek() else {
+            bail!("unexpected end of math");
+        };
+        self.pos += 1;
+        let atom = match c {
+            '\\' => return self.parse_command(),
+            c if c.is_ascii_digit() => {
+                let mut number = c.to_string();
+                while let Some(c) = self.peek() {
+                    let decimal = c == '.'
+                        && self
+                            .chars
+                            .get(self.pos + 1)
+                            .is_some_and(char::is_ascii_digit);
+                    if !c.is_ascii_digit() && !decimal {
+                        break;
+                    }
+                    number.push(c);
+                    self.pos += 1;
+                }
+                number
+            }
+            // commas and semicolons would separate the arguments of typst functions
+            '/' | '"' | '#' | '$' | '@' | '`' | '&' | ',' | ';' => format!("\\{}", c),
+            '~' => "space.nobreak".to_string(),
+            '{' | '}' | '^' | '_' => bail!("unexpected {}", c),
+            c => c.to_string(),
+        };
+        Ok(Some(atom))
+    }
+
+    fn parse_command(&mut self) -> Result<Option<String>> {
+        let mut name = String::new();
+        while let Some(c) = self.peek().filter(char::is_ascii_alphabetic) {
+            name.push(c);
+            self.pos += 1;
+        }
+        if name.is_empty() {
+            // a control symbol like `\,` or `\{`
+            let Some(c) = self.peek() else {
+                bail!("trailing backslash");
+            };
+            self.pos += 1;
+            name.push(c);
+        }
+        if let Some(s) = greek(&name).or_else(|| symbol(&name)) {
+            return Ok(Some(s.to_string()));
+        }
+        if OPERATORS.contains(&name.as_str()) {
+            return Ok(Some(name));
+        }
+        if let Some(f) = function(&name) {
+            if name == "sqrt" {
+                self.skip_whitespace();
+                if self.peek() == Some('[') {
+                    self.pos += 1;
+                    let mut index = Vec::new();
+                    while self.peek().is_some_and(|c| c != ']') {
+                        self.skip_whitespace();
+                        index.extend(self.parse_atom()?);
+                        self.skip_whitespace();
+                    }
+                    self.expect(']')?;
+                    let radicand = self.parse_call_argument()?;
+                    return Ok(Some(format!("root({}, {})", join_atoms(&index), radicand)));
+                }
+            }
+            return Ok(Some(format!("{}({})", f, self.parse_call_argument()?)));
+        }
+        let atom = match name.as_str() {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let num = self.parse_call_argument()?;
+                let denom = self.parse_call_argument()?;
+                format!("frac({}, {})", num, denom)
+            }
+            "binom" => {
+                let n = self.parse_call_argument()?;
+                let k = self.parse_call_argument()?;
+                format!("binom({}, {})", n, k)
+            }
+            "text" | "textrm" | "textnormal" | "mbox" | "textit" | "textbf" => {
+                let text = string_literal(&self.parse_verbatim()?);
+                match name.as_str() {
+                    "textit" => format!("italic({})", text),
+                    "textbf" => format!("bold({})", text),
+                    _ => text,
+                }
+            }
+            "mathrm" | "operatorname" => {
+                let arg = self.parse_verbatim()?;
+                let arg = arg.trim();
+                if arg.chars().all(char::is_alphabetic) {
+                    if name == "operatorname" {
+                        format!("op({})", string_literal(arg))
+                    } else {
+                        string_literal(arg)
+                    }
+                } else {
+                    format!("upright({})", to_typst_math(arg)?)
+                }
+            }
+            "left" | "right" | "bigl" | "bigr" | "Bigl" | "Bigr" | "big" | "Big" | "bigg"
+            | "Bigg" => {
+                // typst scales matching delimiters itself
+                self.skip_whitespace();
+                if self.peek() == Some('.') {
+                    self.pos += 1;
+                    return Ok(None);
+                }
+                return self.parse_atom();
+            }
+            "displaystyle" | "textstyle" | "scriptstyle" | "limits" | "nolimits" | "!" => {
+                return Ok(None);
+            }
+            _ => return Err(anyhow!("unsupported LaTeX command \\{}", name)),
+        };
+        Ok(Some(atom))
+    }
+}
+
+fn wrap(atoms: &[String]) -> String {
+    match atoms {
+        [atom] if is_simple(atom) => atom.clone(),
+        _ => format!("({})", join_atoms(atoms)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fractions() {
+        assert_eq!(to_typst_math(r"\frac{a}{b}").unwrap(), "frac(a, b)");
+        assert_eq!(to_typst_math(r"\frac{1}{2}").unwrap(), "frac(1, 2)");
+    }
+
+    #[test]
+    fn sub_and_superscripts() {
+        assert_eq!(to_typst_math("x_i^2").unwrap(), "x_i^2");
+        assert_eq!(to_typst_math("x_1").unwrap(), "x_1");
+        assert_eq!(to_typst_math(r"\alpha_{ij}").unwrap(), "alpha_(i j)");
+        assert_eq!(to_typst_math("e^{-x}").unwrap(), "e^(- x)");
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(to_typst_math(r"\sqrt{x}").unwrap(), "sqrt(x)");
+        assert_eq!(to_typst_math(r"\mathbb{R}").unwrap(), "bb(R)");
+        assert_eq!(to_typst_math(r"a \cdot b").unwrap(), "a dot b");
+    }
+
+    #[test]
+    fn unknown_commands_fail() {
+        assert!(to_typst_math(r"\foo x").is_err());
+    }
+
+    #[test]
+    fn math_delimiters() {
+        assert_eq!(
+            split_math(r"price $5 and $\alpha$ \(x\)"),
+            [
+                Segment::Text("price $5 and "),
+                Segment::Math {
+                    source: r"$\alpha$",
+                    latex: r"\alpha"
+                },
+                Segment::Text(" "),
+                Segment::Math {
+                    source: r"\(x\)",
+                    latex: "x"
+                },
+            ]
+        );
+    }
+}
@@ -1,6 +1,24 @@
-use log::debug;
+mod clip;
+mod defs;
+mod emit;
+mod fonts;
+mod geometry;
+mod glyphs;
+mod html;
+mod image;
+mod latex;
+mod markers;
+mod mask;
+mod text;
+mod typst;
+
+use log::{debug, warn};
 use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt,
     io::{self, Read},
+    path::PathBuf,
     str::FromStr,
 };
 
@@ -8,10 +26,29 @@ use anyhow::{Result, bail};
 use clap::Parser;
 use quick_xml::{
     Reader,
+    escape::{resolve_predefined_entity, unescape_with},
     events::{BytesStart, Event},
+    name::QName,
 };
 use svgtypes::{SimplifyingPathParser, Transform};
 
+use crate::{
+    clip::{Region, intersect},
+    defs::{Definitions, property},
+    emit::{Backend, Emitter},
+    fonts::FontMap,
+    geometry::{Segment, Subpath, ellipse_segments, path_outline, shape_bounds},
+    glyphs::GlyphText,
+    html::read_foreign_object,
+    image::{ImageOptions, emit_image, image_bounds},
+    markers::PathMarkers,
+    mask::Mask,
+    text::{
+        TextLayout, TextMerge, TextMode, TextOptions, TextPath, TextPositions, TextTransform,
+        TextWrap, TextWriter, WhiteSpace, WritingMode, read_flow_region,
+    },
+};
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -23,6 +60,46 @@ struct Args {
 
     #[arg(long, default_value_t = 0.1)]
     px_scale: f64,
+
+    /// Map a font family to typst fonts, e.g. `--font-map 'sans-serif=Inter,Noto Sans'`.
+    /// An empty font list drops the family.
+    #[arg(long, value_name = "FAMILY=FONTS")]
+    font_map: Vec<String>,
+
+    /// File with one `family = font[, font...]` mapping per line
+    #[arg(long)]
+    font_map_file: Option<PathBuf>,
+
+    /// How text content is written to typst
+    #[arg(long, value_enum, default_value_t = TextMode::Markup)]
+    text_mode: TextMode,
+
+    /// Keep LaTeX math like `$\alpha_i$` as literal text instead of typst math
+    #[arg(long)]
+    no_latex_math: bool,
+
+    /// Merge separately positioned glyphs, as written by pdf converters, into words
+    /// or lines
+    #[arg(long, value_enum, default_value_t = TextMerge::None)]
+    merge_text: TextMerge,
+
+    /// Write text that matplotlib drew as glyph outlines as typst text
+    #[arg(long)]
+    glyph_text: bool,
+
+    /// Write images from `data:` URIs to files in this directory instead of
+    /// embedding their bytes
+    #[arg(long)]
+    image_dir: Option<PathBuf>,
+
+    /// Directory that relative image paths are resolved against, e.g. that of the
+    /// svg file relative to the typst document
+    #[arg(long)]
+    image_base: Option<PathBuf>,
+
+    /// What the drawing is written as
+    #[arg(long, value_enum, default_value_t = Backend::Cetz)]
+    backend: Backend,
 }
 
 fn transform_multiply(ts1: &Transform, ts2: &Transform) -> Transform {
@@ -41,7 +118,115 @@ fn apply_transform(coord: (f64, f64), t: &Transform) -> (f64, f64) {
     (t.a * x + t.c * y + t.e, t.b * x + t.d * y + t.f)
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct TextDecoration {
+    pub underline: bool,
+    pub line_through: bool,
+    pub overline: bool,
+}
+
+impl FromStr for TextDecoration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut r = TextDecoration::default();
+        for token in s.split_whitespace() {
+            match token {
+                "underline" => r.underline = true,
+                "line-through" => r.line_through = true,
+                "overline" => r.overline = true,
+                "none" => r = TextDecoration::default(),
+                // decoration style and color are not representable per-line in typst
+                _ => debug!("Unprocessed text-decoration: {}", token),
+            }
+        }
+        Ok(r)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineHeight {
+    /// Multiple of the font size
+    Factor(f64),
+    /// Absolute length, scaled like font sizes
+    Length(f64),
+}
+
+impl LineHeight {
+    fn parse(s: &str, font_scale: f64) -> Result<Self> {
+        if s == "normal" {
+            // inkscape's default, typical fonts use 1.2 to 1.25
+            Ok(LineHeight::Factor(1.25))
+        } else if let Some(percent) = s.strip_suffix('%') {
+            Ok(LineHeight::Factor(f64::from_str(percent)? / 100.0))
+        } else if let Ok(factor) = f64::from_str(s) {
+            Ok(LineHeight::Factor(factor))
+        } else {
+            Ok(LineHeight::Length(parse_size(s, font_scale)?))
+        }
+    }
+}
+
+/// `letter-spacing` or `word-spacing`, added to the normal spacing
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Spacing {
+    /// Multiple of the font size
+    Em(f64),
+    /// Absolute length, scaled like font sizes
+    Length(f64),
+}
+
+impl Spacing {
+    fn parse(s: &str, font_scale: f64) -> Result<Self> {
+        if s == "normal" {
+            Ok(Spacing::Length(0.0))
+        } else if let Some(em) = s.strip_suffix("em") {
+            Ok(Spacing::Em(f64::from_str(em)?))
+        } else {
+            Ok(Spacing::Length(parse_size(s, font_scale)?))
+        }
+    }
+}
+
+impl fmt::Display for Spacing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Spacing::Em(em) => write!(f, "{:.3}em", em),
+            Spacing::Length(length) => write!(f, "{:.3}pt", length),
+        }
+    }
+}
+
+/// `baseline-shift`, positive values raise the text
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BaselineShift {
+    Sub,
+    Super,
+    /// Multiple of the font size
+    Em(f64),
+    /// Absolute length, scaled like font sizes
+    Length(f64),
+}
+
+impl BaselineShift {
+    fn parse(s: &str, font_scale: f64) -> Result<Self> {
+        match s {
+            "sub" => Ok(BaselineShift::Sub),
+            "super" => Ok(BaselineShift::Super),
+            "baseline" => Ok(BaselineShift::Length(0.0)),
+            // percentages refer to the line height, approximated by the font size
+            _ if s.ends_with('%') => Ok(BaselineShift::Em(
+                f64::from_str(s.trim_end_matches('%'))? / 100.0,
+            )),
+            _ if s.ends_with("em") => {
+                Ok(BaselineShift::Em(f64::from_str(s.trim_end_matches("em"))?))
+            }
+            _ => Ok(BaselineShift::Length(parse_size(s, font_scale)?)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
 struct SvgStyle {
     pub fill: Option<String>,
     pub fill_rule: Option<String>,
@@ -49,39 +234,171 @@ struct SvgStyle {
     pub stroke: Option<String>,
     pub font_family: Option<String>,
     pub font_size: Option<f64>,
+    pub font_weight: Option<u16>,
+    pub font_style: Option<String>,
+    pub small_caps: Option<bool>,
+    pub text_decoration: Option<TextDecoration>,
+    pub line_height: Option<LineHeight>,
+    pub white_space: Option<WhiteSpace>,
+    pub letter_spacing: Option<Spacing>,
+    pub word_spacing: Option<Spacing>,
+    pub baseline_shift: Option<BaselineShift>,
+    pub writing_mode: Option<WritingMode>,
+    /// `direction: rtl`
+    pub rtl: Option<bool>,
+    /// `unicode-bidi: bidi-override`, characters are laid out in their logical order
+    pub bidi_override: Option<bool>,
+    pub text_align: Option<String>,
+    /// `shape-inside`, the reference to the shape text is wrapped in
+    pub shape_inside: Option<String>,
+    /// `shape-padding` and `inline-size`, in user units
+    pub shape_padding: Option<f64>,
+    pub inline_size: Option<f64>,
     pub dash_array: Option<String>,
+    /// Target of an enclosing `a` element, inherited like a property
+    pub link: Option<String>,
+    /// `visibility`, hidden text still takes up its space
+    pub visible: Option<bool>,
 }
 
 impl SvgStyle {
     pub fn format_fill(&self) {
-        if let Some(fill) = &self.fill {
-            print!("fill: {}, ", fill);
-        }
+        print!("{}", self.fill_args());
     }
     pub fn format_stroke(&self) {
+        print!("{}", self.stroke_args());
+    }
+
+    pub fn fill_args(&self) -> String {
+        match &self.fill {
+            Some(fill) => format!("fill: {}, ", fill),
+            None => String::new(),
+        }
+    }
+
+    pub fn stroke_args(&self) -> String {
         if self.stroke.is_some() || self.stroke_width.is_some() || self.dash_array.is_some() {
-            print!("stroke: (");
+            let mut args = "stroke: (".to_string();
             if let Some(stroke) = &self.stroke {
-                print!("paint: {}, ", stroke);
+                args.push_str(&format!("paint: {}, ", stroke));
             }
             if let Some(thickness) = self.stroke_width {
-                print!("thickness: {}pt,", thickness);
+                args.push_str(&format!("thickness: {}pt,", thickness));
             }
             if self.dash_array.is_some() {
-                print!("dash: \"dashed\",")
+                args.push_str("dash: \"dashed\",")
             }
-            print!("),");
+            args.push_str("),");
+            args
         } else {
-            print!("stroke: none, ")
+            "stroke: none, ".to_string()
+        }
+    }
+
+    /// The `fill-rule` argument of paths and polygons, which shapes don't take
+    pub fn fill_rule_args(&self) -> String {
+        match self.fill_rule.as_deref() {
+            Some("evenodd") => "fill-rule: \"even-odd\", ".to_string(),
+            Some("nonzero") => "fill-rule: \"non-zero\", ".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    /// Fills every unset property from `parent`, the way CSS inheritance works for
+    /// nested text content.
+    pub fn inherit(&self, parent: &SvgStyle) -> SvgStyle {
+        SvgStyle {
+            fill: self.fill.clone().or_else(|| parent.fill.clone()),
+            fill_rule: self.fill_rule.clone().or_else(|| parent.fill_rule.clone()),
+            stroke_width: self.stroke_width.or(parent.stroke_width),
+            stroke: self.stroke.clone().or_else(|| parent.stroke.clone()),
+            font_family: self
+                .font_family
+                .clone()
+                .or_else(|| parent.font_family.clone()),
+            font_size: self.font_size.or(parent.font_size),
+            font_weight: self.font_weight.or(parent.font_weight),
+            font_style: self
+                .font_style
+                .clone()
+                .or_else(|| parent.font_style.clone()),
+            small_caps: self.small_caps.or(parent.small_caps),
+            text_decoration: self.text_decoration.or(parent.text_decoration),
+            line_height: self.line_height.or(parent.line_height),
+            white_space: self.white_space.or(parent.white_space),
+            letter_spacing: self.letter_spacing.or(parent.letter_spacing),
+            word_spacing: self.word_spacing.or(parent.word_spacing),
+            // not inherited in css, but the content of a shifted element moves with it
+            baseline_shift: self.baseline_shift.or(parent.baseline_shift),
+            writing_mode: self.writing_mode.or(parent.writing_mode),
+            rtl: self.rtl.or(parent.rtl),
+            bidi_override: self.bidi_override.or(parent.bidi_override),
+            text_align: self
+                .text_align
+                .clone()
+                .or_else(|| parent.text_align.clone()),
+            shape_inside: self
+                .shape_inside
+                .clone()
+                .or_else(|| parent.shape_inside.clone()),
+            shape_padding: self.shape_padding.or(parent.shape_padding),
+            inline_size: self.inline_size.or(parent.inline_size),
+            dash_array: self
+                .dash_array
+                .clone()
+                .or_else(|| parent.dash_array.clone()),
+            link: self.link.clone().or_else(|| parent.link.clone()),
+            visible: self.visible.or(parent.visible),
         }
     }
 }
 
 fn parse_size(size_str: &str, scale: f64) -> Result<f64> {
-    if size_str.ends_with("px") {
-        return Ok(f64::from_str(&size_str[..size_str.len() - 2])? * scale);
-    } else {
-        return Ok(f64::from_str(&size_str)? * scale);
+    let size_str = size_str.strip_suffix("px").unwrap_or(size_str);
+    Ok(f64::from_str(size_str)? * scale)
+}
+
+/// Parses a list of lengths or numbers separated by whitespace and/or commas.
+fn parse_size_list(list_str: &str, scale: f64) -> Result<Vec<f64>> {
+    list_str
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|i| !i.is_empty())
+        .map(|i| parse_size(i, scale))
+        .collect()
+}
+
+fn parse_font_size(size_str: &str, scale: f64) -> Result<f64> {
+    // absolute-size keywords, using the CSS default of medium = 16px
+    let px = match size_str {
+        "xx-small" => 9.0,
+        "x-small" => 10.0,
+        "small" => 13.0,
+        "medium" => 16.0,
+        "large" => 18.0,
+        "x-large" => 24.0,
+        "xx-large" => 32.0,
+        _ => return parse_size(size_str, scale),
+    };
+    Ok(px * scale)
+}
+
+fn parse_font_weight(weight_str: &str) -> Option<u16> {
+    match weight_str {
+        "normal" => Some(400),
+        "bold" => Some(700),
+        // relative weights resolve against the inherited weight, approximate them
+        "bolder" => Some(700),
+        "lighter" => Some(300),
+        _ => u16::from_str(weight_str)
+            .ok()
+            .filter(|w| (1..=1000).contains(w)),
+    }
+}
+
+fn parse_font_style(style_str: &str) -> Option<String> {
+    match style_str.split_whitespace().next() {
+        Some(s @ ("normal" | "italic" | "oblique")) => Some(s.to_string()),
+        _ => None,
     }
 }
 
@@ -89,34 +406,7 @@ impl FromStr for SvgStyle {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut r = SvgStyle::default();
-        for kv_str in s.split(';') {
-            let mut split = kv_str.split(':');
-            if let Some(key) = split.next()
-                && let Some(value) = split.next()
-            {
-                if key == "fill" {
-                    r.fill = Some(value.to_string());
-                } else if key == "fill-rule" {
-                    r.fill_rule = Some(value.to_string());
-                } else if key == "stroke-width" {
-                    r.stroke_width = Some(f64::from_str(&value[..value.len() - 2])?);
-                } else if key == "stroke" {
-                    r.stroke = Some(value.to_string());
-                } else if key == "font-family" {
-                    r.font_family = Some(value.to_string());
-                } else if key == "font-size" {
-                    r.font_size = Some(parse_size(&value, 1.0)?);
-                } else if key == "stroke-dasharray" {
-                    r.dash_array = Some(value.to_string());
-                } else {
-                    debug!("Unprocessed style: {}", kv_str);
-                }
-            } else if !kv_str.is_empty() {
-                return Err(anyhow::anyhow!("unexpected format {}", kv_str));
-            }
-        }
-        Ok(r)
+        SvgStyle::from_str_with_scale(s, 1.0, 1.0)
     }
 }
 
@@ -125,92 +415,264 @@ impl SvgStyle {
         let mut r = SvgStyle::default();
 
         for kv_str in s.split(';') {
-            let mut split = kv_str.split(':');
-            if let Some(key) = split.next()
-                && let Some(value) = split.next()
-            {
-                if key == "fill" {
-                    r.fill = Some(value.to_string());
-                } else if key == "fill-rule" {
-                    r.fill_rule = Some(value.to_string());
-                } else if key == "stroke-width" {
-                    r.stroke_width = Some(parse_size(value, px_scale)?);
-                } else if key == "stroke" {
-                    r.stroke = Some(value.to_string());
-                } else if key == "font-family" {
-                    r.font_family = Some(value.to_string());
-                } else if key == "font-size" {
-                    r.font_size = Some(parse_size(&value, font_scale)?);
-                } else if key == "stroke-dasharray" {
-                    r.dash_array = Some(value.to_string());
-                } else {
+            if let Some((key, value)) = kv_str.split_once(':') {
+                if !r.set_property(key.trim(), value.trim(), font_scale, px_scale)? {
                     debug!("Unprocessed style: {}", kv_str);
                 }
-            } else if !kv_str.is_empty() {
+            } else if !kv_str.trim().is_empty() {
                 return Err(anyhow::anyhow!("unexpected format {}", kv_str));
             }
         }
         Ok(r)
     }
-}
 
-fn gen_content(pos: (f64, f64), style: &Option<SvgStyle>, text_content: &str) {
-    let (x1, y1) = pos;
-    print!("content(({:.3},{:.3}), ", x1, y1);
-    print!("anchor: \"south-west\",");
-    if let Some(style) = style {
-        print!("text(");
-        if let Some(font_size) = style.font_size {
-            print!("size: {:.3}pt, ", font_size);
-        }
-        if let Some(font_family) = &style.font_family {
-            print!(
-                "font: ({}, ), ",
-                font_family.replace("'", "\"").replace(", monospace", "")
+    /// Sets a presentation attribute of `element` on its attribute style, which the
+    /// first supported one creates.
+    fn set_attribute(
+        style: &mut Option<SvgStyle>,
+        element: &BytesStart,
+        key: &[u8],
+        value: &str,
+        font_scale: f64,
+        px_scale: f64,
+    ) -> Result<()> {
+        let key = str::from_utf8(key)?;
+        let mut s = style.clone().unwrap_or_default();
+        if s.set_property(key, value, font_scale, px_scale)? {
+            *style = Some(s);
+        } else {
+            debug!(
+                "Unprocessed attributes for <{}> {}",
+                str::from_utf8(element.name().as_ref())?,
+                key
             );
         }
-        if let Some(fill) = &style.fill
-            && fill != "none"
-        {
-            print!("fill: {}, ", fill);
+        Ok(())
+    }
+
+    /// Sets a single CSS property, used for both `style` declarations and
+    /// presentation attributes. Returns false if the property is not supported.
+    fn set_property(
+        &mut self,
+        key: &str,
+        value: &str,
+        font_scale: f64,
+        px_scale: f64,
+    ) -> Result<bool> {
+        match key {
+            "fill" => self.fill = Some(value.to_string()),
+            "fill-rule" => self.fill_rule = Some(value.to_string()),
+            "stroke-width" => self.stroke_width = Some(parse_size(value, px_scale)?),
+            "stroke" => self.stroke = Some(value.to_string()),
+            "font-family" => self.font_family = Some(value.to_string()),
+            "font-size" => self.font_size = Some(parse_font_size(value, font_scale)?),
+            "font-weight" => self.font_weight = parse_font_weight(value),
+            "font-style" => self.font_style = parse_font_style(value),
+            "font-variant" => {
+                self.small_caps = Some(value.split_whitespace().any(|v| v == "small-caps"))
+            }
+            "text-decoration" | "text-decoration-line" => {
+                self.text_decoration = Some(TextDecoration::from_str(value)?)
+            }
+            "line-height" => self.line_height = Some(LineHeight::parse(value, font_scale)?),
+            "white-space" | "white-space-collapse" | "xml:space" => {
+                self.white_space = WhiteSpace::parse(value)
+            }
+            "letter-spacing" => self.letter_spacing = Some(Spacing::parse(value, font_scale)?),
+            "word-spacing" => self.word_spacing = Some(Spacing::parse(value, font_scale)?),
+            "baseline-shift" => {
+                self.baseline_shift = Some(BaselineShift::parse(value, font_scale)?)
+            }
+            "writing-mode" => self.writing_mode = WritingMode::parse(value),
+            "direction" => self.rtl = Some(value == "rtl"),
+            "unicode-bidi" => {
+                self.bidi_override = Some(matches!(value, "bidi-override" | "isolate-override"))
+            }
+            "text-align" => self.text_align = Some(value.to_string()),
+            "shape-inside" => self.shape_inside = Some(value.to_string()).filter(|v| v != "none"),
+            "shape-padding" => self.shape_padding = Some(parse_size(value, 1.0)?),
+            "inline-size" => self.inline_size = Some(parse_size(value, 1.0)?).filter(|s| *s > 0.0),
+            "font" => self.set_font_shorthand(value, font_scale)?,
+            "stroke-dasharray" => self.dash_array = Some(value.to_string()),
+            "visibility" => self.visible = Some(value == "visible"),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Parses the `font` shorthand:
+    /// `[style || variant || weight || stretch] size[/line-height] family`.
+    fn set_font_shorthand(&mut self, value: &str, font_scale: f64) -> Result<()> {
+        let mut rest = value.trim();
+        while let Some(token) = rest.split_whitespace().next() {
+            rest = rest[token.len()..].trim_start();
+            match token {
+                "normal" => {}
+                "italic" | "oblique" => self.font_style = Some(token.to_string()),
+                "small-caps" => self.small_caps = Some(true),
+                "ultra-condensed" | "extra-condensed" | "condensed" | "semi-condensed"
+                | "semi-expanded" | "expanded" | "extra-expanded" | "ultra-expanded" => {
+                    debug!("Unprocessed font-stretch: {}", token)
+                }
+                _ if !token.contains(['/', '.']) && parse_font_weight(token).is_some() => {
+                    self.font_weight = parse_font_weight(token);
+                }
+                _ => {
+                    let (size, mut line_height) = match token.split_once('/') {
+                        Some((size, line_height)) => (size, line_height),
+                        None => (token, ""),
+                    };
+                    self.font_size = Some(parse_font_size(size, font_scale)?);
+                    // a spaced out line-height, e.g. `12px / 1.2`
+                    if line_height.is_empty()
+                        && let Some(spaced) = rest.strip_prefix('/')
+                    {
+                        rest = spaced.trim_start();
+                        if let Some(token) = rest.split_whitespace().next() {
+                            rest = rest[token.len()..].trim_start();
+                            line_height = token;
+                        }
+                    }
+                    if !line_height.is_empty() {
+                        self.line_height = Some(LineHeight::parse(line_height, font_scale)?);
+                    }
+                    if !rest.is_empty() {
+                        self.font_family = Some(rest.to_string());
+                    }
+                    return Ok(());
+                }
+            }
         }
-        print!(")")
+        Err(anyhow::anyhow!(
+            "missing font size in font shorthand {}",
+            value
+        ))
     }
-    print!(
-        "[{}]",
-        text_content
-            .replace("$", "\\$")
-            .replace("[", "\\[")
-            .replace("]", "\\]")
-            .replace("/", "\\/")
-            .replace("#", "\\#")
-    );
+}
 
-    print!(")\n");
+/// Combines `style` declarations with presentation attributes, which have lower
+/// priority than any declaration.
+fn merge_styles(declared: Option<SvgStyle>, attrs: Option<SvgStyle>) -> Option<SvgStyle> {
+    match (declared, attrs) {
+        (Some(declared), Some(attrs)) => Some(declared.inherit(&attrs)),
+        (declared, attrs) => declared.or(attrs),
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 struct EventEntry {
     name: Vec<u8>,
     transform: Transform,
-    // tspan may have multiple
-    positions: Option<Vec<(f64, f64)>>,
+    // set for text content elements
+    positions: Option<TextPositions>,
     style: Option<SvgStyle>,
+    // the element's own clip path and mask, those of its ancestors apply as well
+    clip: Option<Region>,
+    mask: Option<Mask>,
+    // target of an `a` element, and the bounds of the shapes drawn inside it as
+    // minimum and maximum x and y in output coordinates
+    link: Option<String>,
+    link_bounds: Cell<Option<[f64; 4]>>,
+    // own `visibility` of containers
+    visible: Option<bool>,
+    // own `marker-start`, `marker-mid` and `marker-end` of containers
+    markers: [Option<String>; 3],
 }
 
+/// The masks of all ancestors and the element's own, combined
+fn combined_mask(events_stack: &[EventEntry], own: Option<Mask>) -> Option<Mask> {
+    events_stack
+        .iter()
+        .filter_map(|e| e.mask.clone())
+        .chain(own)
+        .reduce(Mask::combine)
+}
+
+/// Applies a mask to the fill of text. Only uniform masks can be represented, as
+/// the extent of the text isn't known.
+fn mask_text(style: &mut Option<SvgStyle>, mask: Option<Mask>) {
+    match mask {
+        Some(mask @ Mask::Opacity(_)) => {
+            let style = style.get_or_insert_default();
+            style.fill.get_or_insert_with(|| "black".to_string());
+            mask.apply(style, None);
+        }
+        Some(_) => warn!("Gradient masks on text can't be represented, the text is drawn unmasked"),
+        None => {}
+    }
+}
+
+/// The clip paths of all ancestors and the element's own
+fn clip_regions(events_stack: &[EventEntry], own: Option<Region>) -> Vec<Region> {
+    events_stack
+        .iter()
+        .filter_map(|e| e.clip.clone())
+        .chain(own)
+        .collect()
+}
+
+/// Writes an outline cut to the clip paths, returns false when clipping leaves it
+/// unchanged and it should be drawn as usual.
+fn emit_clipped(
+    regions: &[Region],
+    outline: &[Subpath],
+    style: Option<&SvgStyle>,
+    emitter: &dyn Emitter,
+) -> bool {
+    if regions.is_empty() || clip::covers(regions, outline) {
+        return false;
+    }
+    clip::emit_clipped(outline, style, regions, emitter);
+    true
+}
+
+/// Adds a shape's outline to the bounds of the innermost link around it.
+fn extend_link_bounds(events_stack: &[EventEntry], outline: &[Subpath]) {
+    let Some(entry) = events_stack.iter().rev().find(|e| e.link.is_some()) else {
+        return;
+    };
+    for segment in outline.iter().flat_map(|s| &s.segments) {
+        let points = match *segment {
+            Segment::Line(a, b) => vec![a, b],
+            // control points bound the curve
+            Segment::Cubic(p0, p1, p2, p3) => vec![p0, p1, p2, p3],
+        };
+        for (x, y) in points {
+            let bounds = match entry.link_bounds.get() {
+                Some(b) => [b[0].min(x), b[1].min(y), b[2].max(x), b[3].max(y)],
+                None => [x, y, x, y],
+            };
+            entry.link_bounds.set(Some(bounds));
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn process_element(
     element: &BytesStart,
     events_stack: &mut Vec<EventEntry>,
     reader: &mut Reader<&[u8]>,
     font_scale: f64,
     px_scale: f64,
+    defs: &Definitions,
+    images: &ImageOptions,
+    emitter: &dyn Emitter,
 ) -> Result<()> {
     let parent = events_stack.last().unwrap();
+    if matches!(
+        element.name().as_ref(),
+        b"rect" | b"path" | b"ellipse" | b"circle" | b"image"
+    ) && !is_visible(element, events_stack)?
+    {
+        return Ok(());
+    }
     match element.name().as_ref() {
-        b"g" => {
+        // links outside of text are transparent containers
+        b"g" | b"switch" | b"a" if parent.positions.is_none() => {
+            let mut cur_transform = parent.transform;
+            let mut link = None;
             for attr_result in element.attributes() {
                 let a = attr_result?;
-                let mut cur_transform = events_stack.last().unwrap().transform.clone();
                 match a.key.as_ref() {
                     b"transform" => {
                         let transform_str = a.decode_and_unescape_value(reader.decoder())?;
@@ -220,141 +682,162 @@ fn process_element(
                             &Transform::from_str(transform_str.as_ref())?,
                         );
                         debug!("cur_transform {:?}", cur_transform);
-                        // handle_event(sub_event, reader, &cur_transform)?;
                     }
+                    b"href" | b"xlink:href" if element.name().as_ref() == b"a" => {
+                        link = Some(a.unescape_value()?.to_string());
+                    }
+                    b"clip-path" | b"mask" | b"marker-start" | b"marker-mid" | b"marker-end" => {}
                     _ => debug!(
                         "Unprocessed attr for <g> {}",
                         str::from_utf8(a.key.as_ref())?
                     ),
                 }
-                events_stack.push(EventEntry {
-                    name: Vec::from(element.name().as_ref()),
-                    transform: cur_transform,
-                    positions: None,
-                    style: None,
-                });
-                // loop {
-                //     let sub_event = reader.read_event_into(&mut event_buf)?;
-                //     if let Event::End(ref e) = sub_event
-                //         && e.name().as_ref() == b"g"
-                //     {
-                //         debug!("End of g :{:?}", sub_event);
-                //         break;
-                //     }
-                //     handle_event(sub_event, reader, &cur_transform, font_scale)?;
-                // }
             }
+            let clip = Region::from_element(element, &cur_transform, None, defs)?;
+            events_stack.push(EventEntry {
+                name: Vec::from(element.name().as_ref()),
+                transform: cur_transform,
+                positions: None,
+                style: None,
+                clip,
+                mask: Mask::from_element(element, defs, None)?,
+                link,
+                link_bounds: Cell::new(None),
+                visible: property(element, "visibility")?.map(|v| v == "visible"),
+                markers: PathMarkers::properties(element)?,
+            });
         }
 
-        b"text" => {
-            let mut x = 0.0;
-            let mut y = 0.0;
+        b"text" | b"flowRoot" => {
+            let mut positions = TextPositions::default();
             let mut style = None;
+            let mut attr_style = None;
+            // the font size depends on the text's own transform, resolve it upfront
+            let transform = match element.try_get_attribute(b"transform")? {
+                Some(a) => transform_multiply(
+                    &parent.transform,
+                    &Transform::from_str(a.decode_and_unescape_value(reader.decoder())?.as_ref())?,
+                ),
+                None => parent.transform,
+            };
+            let text_scale = TextTransform::from_transform(&transform).font_scale();
             for attr in element.attributes() {
                 let a = attr?;
                 let val_cow = a.decode_and_unescape_value(reader.decoder())?;
                 let val_str = val_cow.as_ref();
                 match a.key.as_ref() {
-                    b"x" => {
-                        x = parse_size(val_str, 1.0)?;
-                    }
-                    b"y" => {
-                        y = parse_size(val_str, 1.0)?;
-                    }
+                    b"transform" => {}
+                    key if positions.set_attribute(key, val_str)? => {}
                     b"style" => {
                         style = Some(SvgStyle::from_str_with_scale(
                             val_str,
-                            font_scale * parent.transform.a,
-                            px_scale * parent.transform.a,
+                            font_scale * text_scale,
+                            px_scale * text_scale,
                         )?);
                     }
-                    _ => debug!(
-                        "Unprocessed attributes for <text> {}",
-                        str::from_utf8(a.key.as_ref())?
-                    ),
+                    key => SvgStyle::set_attribute(
+                        &mut attr_style,
+                        element,
+                        key,
+                        val_str,
+                        font_scale * text_scale,
+                        px_scale * text_scale,
+                    )?,
                 }
             }
-            let last_transform = events_stack.last().unwrap().transform.clone();
+            let mut style = merge_styles(style, attr_style);
+            if let Some(link) = events_stack.iter().rev().find_map(|e| e.link.clone()) {
+                style.get_or_insert_default().link = Some(link);
+            }
+            if style.as_ref().and_then(|s| s.visible).is_none()
+                && let Some(visible) = events_stack.iter().rev().find_map(|e| e.visible)
+            {
+                style.get_or_insert_default().visible = Some(visible);
+            }
+            let mask = Mask::from_element(element, defs, None)?;
+            mask_text(&mut style, combined_mask(events_stack, mask.clone()));
+            if property(element, "clip-path")?.is_some_and(|c| c != "none")
+                || events_stack.iter().any(|e| e.clip.is_some())
+            {
+                warn!("Clip paths on text can't be represented, the text is drawn unclipped");
+            }
             events_stack.push(EventEntry {
                 name: Vec::from(element.name().as_ref()),
-                transform: last_transform,
-                positions: Some(vec![(x, y)]),
-                style: style,
+                transform,
+                positions: Some(positions),
+                style,
+                clip: None,
+                mask,
+                link: None,
+                link_bounds: Cell::new(None),
+                visible: None,
+                markers: Default::default(),
             });
-            // loop {
-            //     let evt = reader.read_event_into(&mut event_buf)?;
-            //     if let Event::End(element) = &evt
-            //         && element.name().as_ref() == b"text"
-            //     {
-            //         num_text_end_expected -= 1;
-            //         if num_text_end_expected == 0 {
-            //             break;
-            //         }
-            //     }
-            //     // println!("---- {:?}", evt);
-            //     if let Event::Text(content) = &evt {
-            //         text_content.push_str(str::from_utf8(content.as_ref())?);
-            //     }
-            // }
-            // debug!(
-            //     "text --- x = {}, y = {}, style = {:?}, content = {}",
-            //     x, y, style, text_content
-            // );
-            // let (x1, y1) = apply_transform((x, y), transform);
-            // gen_content(x1, y1, &style, &text_content, font_scale);
         }
-        b"tspan" => {
-            let mut x = Vec::<f64>::new();
-            let mut y = Vec::<f64>::new();
+        b"tspan" | b"textPath" | b"flowPara" | b"flowSpan" | b"flowDiv" | b"a" => {
+            let mut positions = TextPositions::default();
+            let mut link = None;
+            let mut own_style = None;
+            let mut attr_style = None;
+            let text_scale = TextTransform::from_transform(&parent.transform).font_scale();
             for attr in element.attributes() {
                 let a = attr?;
                 let val_cow = a.decode_and_unescape_value(reader.decoder())?;
                 let val_str = val_cow.as_ref();
                 match a.key.as_ref() {
-                    b"x" => {
-                        x = val_str
-                            .split_whitespace()
-                            .filter(|i| !i.is_empty())
-                            .map(|i| {
-                                if i.ends_with("px") {
-                                    f64::from_str(&i[0..i.len() - 2]).unwrap()
-                                } else {
-                                    f64::from_str(i).unwrap()
-                                }
-                            })
-                            .collect();
+                    key if positions.set_attribute(key, val_str)? => {}
+                    b"href" | b"xlink:href" if element.name().as_ref() == b"a" => {
+                        link = Some(val_str.to_string());
                     }
-                    b"y" => {
-                        y = val_str
-                            .split_whitespace()
-                            .filter(|i| !i.is_empty())
-                            .map(|i| {
-                                if i.ends_with("px") {
-                                    f64::from_str(&i[0..i.len() - 2]).unwrap()
-                                } else {
-                                    f64::from_str(i).unwrap()
-                                }
-                            })
-                            .collect();
+                    b"style" => {
+                        own_style = Some(SvgStyle::from_str_with_scale(
+                            val_str,
+                            font_scale * text_scale,
+                            px_scale * text_scale,
+                        )?);
                     }
-                    _ => debug!(
-                        "Unprocessed attributes for <text> {}",
-                        str::from_utf8(a.key.as_ref())?
-                    ),
+                    key => SvgStyle::set_attribute(
+                        &mut attr_style,
+                        element,
+                        key,
+                        val_str,
+                        font_scale * text_scale,
+                        px_scale * text_scale,
+                    )?,
                 }
             }
-            let mut style = None;
-            for e in events_stack.iter().rev() {
-                if let Some(s) = &e.style {
-                    style = Some(s.clone());
-                    break;
-                }
+            // paragraphs of flowed text
+            if element.name().as_ref() == b"flowPara" {
+                positions.new_line = true;
+            }
+            let own_style = merge_styles(own_style, attr_style);
+            // an inherited fill is already masked by the ancestors
+            let own_fill = own_style.as_ref().is_some_and(|s| s.fill.is_some());
+            let inherited = events_stack.iter().rev().find_map(|e| e.style.as_ref());
+            let mut style = match (own_style, inherited) {
+                (Some(own), Some(inherited)) => Some(own.inherit(inherited)),
+                (own, inherited) => own.or_else(|| inherited.cloned()),
+            };
+            if link.is_some() {
+                style.get_or_insert_default().link = link;
+            }
+            let mask = Mask::from_element(element, defs, None)?;
+            if own_fill {
+                mask_text(&mut style, combined_mask(events_stack, mask.clone()));
+            } else {
+                mask_text(&mut style, mask.clone());
             }
             events_stack.push(EventEntry {
                 name: Vec::from(element.name().as_ref()),
-                transform: events_stack.last().unwrap().transform.clone(),
-                positions: Some(x.iter().zip(y.iter()).map(|(i, j)| (*i, *j)).collect()),
+                transform: events_stack.last().unwrap().transform,
+                positions: Some(positions),
                 style,
+                clip: None,
+                mask,
+                link: None,
+                link_bounds: Cell::new(None),
+                visible: None,
+                markers: Default::default(),
             });
         }
 
@@ -388,18 +871,49 @@ fn process_element(
                             px_scale * parent.transform.a,
                         )?;
                     }
+                    b"clip-path" | b"mask" | b"marker-start" | b"marker-mid" | b"marker-end" => {}
                     _ => debug!(
                         "Unprocessed attributes for <rect> {}",
                         str::from_utf8(a.key.as_ref())?
                     ),
                 }
             }
+            // shapes without area aren't rendered
+            if width <= 0.0 || height <= 0.0 {
+                return Ok(());
+            }
+            let bbox = Some([x, y, width, height]);
+            if let Some(mask) =
+                combined_mask(events_stack, Mask::from_element(element, defs, bbox)?)
+            {
+                mask.apply(&mut style, bbox);
+            }
+            let own_clip = Region::from_element(element, &parent.transform, bbox, defs)?;
+            let corners = [
+                (x, y),
+                (x + width, y),
+                (x + width, y + height),
+                (x, y + height),
+            ]
+            .map(|p| apply_transform(p, &parent.transform));
+            let outline = [Subpath {
+                segments: (0..4)
+                    .map(|i| Segment::Line(corners[i], corners[(i + 1) % 4]))
+                    .collect(),
+                closed: true,
+            }];
+            extend_link_bounds(events_stack, &outline);
+            if emit_clipped(
+                &clip_regions(events_stack, own_clip),
+                &outline,
+                Some(&style),
+                emitter,
+            ) {
+                return Ok(());
+            }
             let (x1, y1) = apply_transform((x, y), &parent.transform);
             let (x2, y2) = apply_transform((x + width, y + height), &parent.transform);
-            print!("rect(({:.3}, {:.3}), ({:.3}, {:.3}), ", x1, y1, x2, y2);
-            style.format_fill();
-            style.format_stroke();
-            print!(")\n");
+            emitter.rect((x1, y1), (x2, y2), Some(&style));
         }
         b"path" => {
             let mut path_segments = None;
@@ -411,7 +925,7 @@ fn process_element(
                     b"d" => {
                         let mut segments = Vec::new();
                         let mut parser = SimplifyingPathParser::from(val_str.as_ref());
-                        while let Some(path_segment) = parser.next() {
+                        for path_segment in parser.by_ref() {
                             segments.push(path_segment?);
                         }
                         path_segments = Some(segments);
@@ -431,6 +945,7 @@ fn process_element(
                             style.fill = Some(val_str.to_string());
                         }
                     }
+                    b"clip-path" | b"mask" | b"marker-start" | b"marker-mid" | b"marker-end" => {}
                     _ => {
                         debug!("unprocessed attr {:?}", a);
                     }
@@ -438,111 +953,37 @@ fn process_element(
             }
             debug!("d={:?}, style={:?}", path_segments, style);
             if let Some(segments) = &path_segments {
-                let mut unclosed_point = None;
-                let mut last_point = (0.0, 0.0);
-                let mut merge_path = false;
-                let mut compound_path = false;
-
-                if let Some(style) = &style
-                    && let Some(fill) = &style.fill
-                    && fill != "none"
+                let bbox = shape_bounds(element)?;
+                if let Some(mask) =
+                    combined_mask(events_stack, Mask::from_element(element, defs, bbox)?)
+                    && let Some(style) = &mut style
                 {
-                    compound_path = true;
-                    print!("compound-path(");
-                    style.format_fill();
-                    style.format_stroke();
-                    print!("{{\n");
-                }
-                for s in segments {
-                    match s {
-                        svgtypes::SimplePathSegment::MoveTo { x, y } => {
-                            last_point = apply_transform((*x, *y), &parent.transform);
-                        }
-                        svgtypes::SimplePathSegment::LineTo { x, y } => {
-                            if unclosed_point.is_none() {
-                                if let Some(style) = &style
-                                    && let Some(fill) = &style.fill
-                                    && fill != "none"
-                                {
-                                    merge_path = true;
-                                    print!("merge-path(");
-                                    style.format_fill();
-                                    style.format_stroke();
-                                    print!("{{\n");
-                                }
-                                unclosed_point = Some(last_point);
-                            }
-                            let (x, y) = apply_transform((*x, *y), &parent.transform);
-                            print!(
-                                "line(({:.3}, {:.3}), ({:.3}, {:.3}),",
-                                last_point.0, last_point.1, x, y
-                            );
-                            if let Some(style) = &style {
-                                style.format_stroke();
-                            }
-                            print!(")\n");
-                            last_point = (x, y);
-                        }
-                        svgtypes::SimplePathSegment::CurveTo {
-                            x1,
-                            y1,
-                            x2,
-                            y2,
-                            x,
-                            y,
-                        } => {
-                            if unclosed_point.is_none() {
-                                if let Some(style) = &style
-                                    && let Some(fill) = &style.fill
-                                    && fill != "none"
-                                {
-                                    merge_path = true;
-                                    print!("merge-path(");
-                                    style.format_fill();
-                                    style.format_stroke();
-                                    print!("{{\n");
-                                }
-                                unclosed_point = Some(last_point);
-                            }
-                            let (x1, y1) = apply_transform((*x1, *y1), &parent.transform);
-                            let (x2, y2) = apply_transform((*x2, *y2), &parent.transform);
-                            let (x, y) = apply_transform((*x, *y), &parent.transform);
-                            print!(
-                                "bezier(({:.3}, {:.3}), ({:.3}, {:.3}), ({:.3}, {:.3}), ({:.3}, {:.3}),",
-                                last_point.0, last_point.1, x, y, x1, y1, x2, y2,
-                            );
-                            if let Some(style) = &style {
-                                style.format_stroke();
-                            }
-                            print!(")\n");
-                            last_point = (x, y);
-                        }
-                        svgtypes::SimplePathSegment::ClosePath => {
-                            if let Some((x, y)) = unclosed_point {
-                                print!(
-                                    "line(({:.3}, {:.3}), ({:.3}, {:.3}),",
-                                    last_point.0, last_point.1, x, y
-                                );
-                                if let Some(style) = &style {
-                                    style.format_stroke();
-                                }
-                                print!(")\n");
-                                unclosed_point = None;
-                            }
-                            if merge_path {
-                                println!("}})");
-                                merge_path = false;
-                            }
-                        }
-                        _ => todo!(),
-                    }
-                }
-                if merge_path {
-                    println!("}})");
+                    mask.apply(style, bbox);
                 }
-                if compound_path {
-                    println!("}})");
+                let own_clip = Region::from_element(element, &parent.transform, bbox, defs)?;
+                let markers = PathMarkers::resolve(
+                    element,
+                    inherited_markers(events_stack),
+                    segments,
+                    &parent.transform,
+                    style.as_ref(),
+                    defs,
+                    px_scale,
+                    emitter.has_marks(),
+                )?;
+                let outline = path_outline(segments, &parent.transform);
+                extend_link_bounds(events_stack, &outline);
+                if emit_clipped(
+                    &clip_regions(events_stack, own_clip),
+                    &outline,
+                    style.as_ref(),
+                    emitter,
+                ) {
+                    markers.emit(emitter);
+                    return Ok(());
                 }
+                emitter.path(&outline, style.as_ref(), Some(&markers));
+                markers.emit(emitter);
             }
         }
         b"ellipse" => {
@@ -575,6 +1016,7 @@ fn process_element(
                             px_scale * parent.transform.a,
                         )?);
                     }
+                    b"clip-path" | b"mask" | b"marker-start" | b"marker-mid" | b"marker-end" => {}
                     _ => debug!(
                         "Unprocessed attributes for <rect> {}",
                         str::from_utf8(a.key.as_ref())?
@@ -582,20 +1024,34 @@ fn process_element(
                 }
             }
             // println!("{:?}", transform);
+            // shapes without area aren't rendered
+            if rx <= 0.0 || ry <= 0.0 {
+                return Ok(());
+            }
+            let bbox = Some([cx - rx, cy - ry, 2.0 * rx, 2.0 * ry]);
+            if let Some(mask) =
+                combined_mask(events_stack, Mask::from_element(element, defs, bbox)?)
+                && let Some(style) = &mut style
+            {
+                mask.apply(style, bbox);
+            }
+            let own_clip = Region::from_element(element, &parent.transform, bbox, defs)?;
+            let outline = [Subpath {
+                segments: ellipse_segments(cx, cy, rx, ry, &parent.transform),
+                closed: true,
+            }];
+            extend_link_bounds(events_stack, &outline);
+            if emit_clipped(
+                &clip_regions(events_stack, own_clip),
+                &outline,
+                style.as_ref(),
+                emitter,
+            ) {
+                return Ok(());
+            }
             let (cx1, cy1) = apply_transform((cx, cy), &parent.transform);
             let (rx1, ry1) = apply_transform((cx + rx, cy + ry), &parent.transform);
-            print!(
-                "circle(({:.3}, {:.3}), radius: ({:.3}, {:.3}), ",
-                cx1,
-                cy1,
-                rx1 - cx1,
-                ry1 - cy1,
-            );
-            if let Some(style) = &style {
-                style.format_fill();
-                style.format_stroke();
-            }
-            print!(")\n")
+            emitter.ellipse((cx1, cy1), (rx1 - cx1, ry1 - cy1), style.as_ref());
         }
         b"circle" => {
             let mut cx = 0.0;
@@ -623,6 +1079,7 @@ fn process_element(
                             px_scale * parent.transform.a,
                         )?);
                     }
+                    b"clip-path" | b"mask" | b"marker-start" | b"marker-mid" | b"marker-end" => {}
                     _ => debug!(
                         "Unprocessed attributes for <rect> {}",
                         str::from_utf8(a.key.as_ref())?
@@ -630,14 +1087,53 @@ fn process_element(
                 }
             }
             // println!("{:?}", transform);
+            // shapes without area aren't rendered
+            if r <= 0.0 {
+                return Ok(());
+            }
+            let bbox = Some([cx - r, cy - r, 2.0 * r, 2.0 * r]);
+            if let Some(mask) =
+                combined_mask(events_stack, Mask::from_element(element, defs, bbox)?)
+                && let Some(style) = &mut style
+            {
+                mask.apply(style, bbox);
+            }
+            let own_clip = Region::from_element(element, &parent.transform, bbox, defs)?;
+            let outline = [Subpath {
+                segments: ellipse_segments(cx, cy, r, r, &parent.transform),
+                closed: true,
+            }];
+            extend_link_bounds(events_stack, &outline);
+            if emit_clipped(
+                &clip_regions(events_stack, own_clip),
+                &outline,
+                style.as_ref(),
+                emitter,
+            ) {
+                return Ok(());
+            }
             let (cx1, cy1) = apply_transform((cx, cy), &parent.transform);
             let (rx1, _) = apply_transform((cx + r, cy + r), &parent.transform);
-            print!("circle(({:.3}, {:.3}), radius: {}, ", cx1, cy1, rx1 - cx1,);
-            if let Some(style) = &style {
-                style.format_fill();
-                style.format_stroke();
+            emitter.circle((cx1, cy1), rx1 - cx1, style.as_ref());
+        }
+        b"image" => {
+            if combined_mask(events_stack, Mask::from_element(element, defs, None)?).is_some() {
+                warn!("Masks on images can't be represented, the image is drawn unmasked");
+            }
+            let own_clip =
+                Region::from_element(element, &parent.transform, image_bounds(element)?, defs)?;
+            let regions = clip_regions(events_stack, own_clip);
+            if regions.iter().any(|r| !r.is_rectangle()) {
+                warn!("Image cut to the bounds of a clip path that isn't a rectangle");
             }
-            print!(")\n")
+            let clip = regions
+                .iter()
+                .map(Region::bounds)
+                .reduce(|a, b| intersect(a?, b?));
+            if clip == Some(None) {
+                return Ok(());
+            }
+            emit_image(element, &parent.transform, clip.flatten(), images, emitter)?
         }
         _ => {
             debug!(
@@ -649,89 +1145,337 @@ fn process_element(
     Ok(())
 }
 
+/// Adds character data to the current text, positioned and styled by the innermost
+/// text content element.
+fn push_char_data(
+    char_data: &str,
+    text_layout: &mut Option<TextLayout>,
+    events_stack: &[EventEntry],
+) -> Result<()> {
+    let Some(layout) = text_layout else {
+        if !char_data.trim().is_empty() {
+            debug!("Text outside of <text>: {:?}", char_data);
+        }
+        return Ok(());
+    };
+    let Some(parent) = events_stack.iter().rev().find(|e| e.positions.is_some()) else {
+        bail!("Can't find parent for text {:?}", char_data);
+    };
+    // flowed text only has content in its paragraphs
+    if matches!(parent.name.as_slice(), b"flowRoot" | b"flowDiv") {
+        return Ok(());
+    }
+    // innermost first
+    let positions: Vec<&TextPositions> = events_stack
+        .iter()
+        .rev()
+        .filter_map(|e| e.positions.as_ref())
+        .collect();
+    layout.push_text(char_data, &parent.style, &positions);
+    Ok(())
+}
+
+/// Collects the internal entities declared in a DOCTYPE, with character references
+/// and earlier entities in their values resolved.
+fn parse_entity_declarations(doctype: &str) -> HashMap<String, String> {
+    let mut entities = HashMap::new();
+    let mut rest = doctype;
+    while let Some(start) = rest.find("<!ENTITY") {
+        rest = &rest[start + "<!ENTITY".len()..];
+        let decl = rest.trim_start();
+        // parameter entities only apply within the DTD
+        if decl.starts_with('%') {
+            continue;
+        }
+        let Some(name_end) = decl.find(char::is_whitespace) else {
+            break;
+        };
+        let name = &decl[..name_end];
+        let value = decl[name_end..].trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            // external entities are not loaded
+            debug!("Unsupported entity declaration {}", name);
+            continue;
+        };
+        let Some(value_end) = value[1..].find(quote) else {
+            break;
+        };
+        let value = &value[1..value_end + 1];
+        let resolved = unescape_with(value, |entity| {
+            resolve_predefined_entity(entity).or(entities.get(entity).map(String::as_str))
+        })
+        .map(|v| v.into_owned())
+        .unwrap_or_else(|_| value.to_string());
+        entities.insert(name.to_string(), resolved);
+    }
+    entities
+}
+
+/// Whether an element and its children are drawn at all: `display: none` and
+/// `opacity: 0` hide them, whatever the children's own properties.
+fn is_rendered(element: &BytesStart) -> Result<bool> {
+    if property(element, "display")?.is_some_and(|d| d == "none") {
+        return Ok(false);
+    }
+    let Some(opacity) = property(element, "opacity")? else {
+        return Ok(true);
+    };
+    let parsed = match opacity.strip_suffix('%') {
+        Some(percent) => f64::from_str(percent.trim()).map(|p| p / 100.0),
+        None => f64::from_str(opacity.trim()),
+    };
+    match parsed {
+        Ok(opacity) => Ok(opacity > 0.0),
+        // `inherit`, `initial`, `unset` and the like
+        Err(_) => {
+            debug!("Ignoring opacity {}", opacity);
+            Ok(true)
+        }
+    }
+}
+
+/// `visibility`, which is inherited but can be overridden by children
+fn is_visible(element: &BytesStart, events_stack: &[EventEntry]) -> Result<bool> {
+    Ok(match property(element, "visibility")? {
+        Some(visibility) => visibility == "visible",
+        None => events_stack
+            .iter()
+            .rev()
+            .find_map(|e| e.visible)
+            .unwrap_or(true),
+    })
+}
+
+/// The marker properties inherited from the containers of an element
+fn inherited_markers(events_stack: &[EventEntry]) -> [Option<String>; 3] {
+    std::array::from_fn(|i| events_stack.iter().rev().find_map(|e| e.markers[i].clone()))
+}
+
+/// Whether an element may draw something, so that text before it can no longer be
+/// merged with text after it
+fn draws_over_text(name: &[u8]) -> bool {
+    !matches!(
+        name,
+        b"svg" | b"g" | b"a" | b"text" | b"tspan" | b"title" | b"desc" | b"metadata"
+    )
+}
+
 fn handle_event(
     reader: &mut Reader<&[u8]>,
     root_transform: &Transform,
     font_scale: f64,
     px_scale: f64,
+    text_options: &TextOptions,
+    defs: &Definitions,
+    images: &ImageOptions,
 ) -> Result<()> {
+    let emitter = text_options.backend.emitter();
     let mut events_stack = vec![EventEntry {
         name: Vec::from(b"root"),
-        transform: root_transform.clone(),
+        transform: *root_transform,
         positions: Default::default(),
         style: Default::default(),
+        clip: None,
+        mask: None,
+        link: None,
+        link_bounds: Cell::new(None),
+        visible: None,
+        markers: Default::default(),
     }];
+    // the characters of the current <text> element
+    let mut text_layout: Option<TextLayout> = None;
+    let mut text_writer = TextWriter::default();
+    // the matplotlib text group being read, with glyph text recovery
+    let mut glyph_text: Option<GlyphText> = None;
+    // character data is accumulated until markup other than references interrupts it
+    let mut char_data = String::new();
+    let mut entities = HashMap::new();
     let mut event_buf = Vec::new();
     loop {
         let event = reader.read_event_into(&mut event_buf)?;
+        if !matches!(
+            event,
+            Event::Text(_) | Event::CData(_) | Event::GeneralRef(_) | Event::Comment(_)
+        ) && !char_data.is_empty()
+        {
+            push_char_data(&char_data, &mut text_layout, &events_stack)?;
+            char_data.clear();
+        }
         match event {
             Event::Eof => {
+                text_writer.flush(text_options);
                 break;
             }
             Event::End(element) => {
-                events_stack.pop_if(|item| &item.name == element.name().as_ref());
+                let entry = events_stack.pop_if(|item| item.name == element.name().as_ref());
+                if let Some(entry) = &entry
+                    && let (Some(link), Some([x0, y0, x1, y1])) =
+                        (&entry.link, entry.link_bounds.get())
+                {
+                    emitter.link((x0, y0), (x1, y1), link);
+                }
+                if let Some(layout) = &mut text_layout
+                    && let Some(positions) = entry.and_then(|e| e.positions)
+                {
+                    layout.end_element(&positions);
+                }
+                if let Some(layout) = &mut text_layout
+                    && element.name().as_ref() == b"textPath"
+                {
+                    layout.end_path();
+                }
+                if matches!(element.name().as_ref(), b"text" | b"flowRoot")
+                    && let Some(layout) = text_layout.take()
+                {
+                    text_writer.write(&layout, text_options);
+                }
+                if let Some(glyphs) = glyph_text.take_if(|g| g.depth > events_stack.len()) {
+                    glyphs.emit(font_scale, text_options, &mut text_writer);
+                }
             }
             Event::Start(element) => {
-                process_element(&element, &mut events_stack, reader, font_scale, px_scale)?;
+                if !is_rendered(&element)? {
+                    reader.read_to_end_into(element.name(), &mut Vec::new())?;
+                    continue;
+                }
+                if draws_over_text(element.name().as_ref()) {
+                    text_writer.flush(text_options);
+                }
+                if let Some(layout) = &mut text_layout
+                    && element.name().as_ref() == b"flowRegion"
+                {
+                    layout.wrap = read_flow_region(reader)?;
+                    continue;
+                }
+                if element.name().as_ref() == b"foreignObject" {
+                    let parent = events_stack.last().unwrap();
+                    let shown = read_foreign_object(
+                        reader,
+                        &element,
+                        &parent.transform,
+                        font_scale,
+                        text_options,
+                    )?;
+                    if shown && parent.name == b"switch" {
+                        // the other children of a switch are fallbacks
+                        reader.read_to_end(QName(b"switch"))?;
+                        events_stack.pop();
+                    }
+                    continue;
+                }
+                if matches!(
+                    element.name().as_ref(),
+                    b"defs"
+                        | b"clipPath"
+                        | b"mask"
+                        | b"marker"
+                        | b"linearGradient"
+                        | b"radialGradient"
+                ) {
+                    // definitions are only drawn where they are referenced
+                    reader.read_to_end_into(element.name(), &mut Vec::new())?;
+                    continue;
+                }
+                if let Some(glyphs) = &mut glyph_text {
+                    match element.name().as_ref() {
+                        b"g" => glyphs.group(&element)?,
+                        b"use" => {
+                            glyphs.glyph(&element, &events_stack.last().unwrap().transform)?
+                        }
+                        _ => {}
+                    }
+                }
+                process_element(
+                    &element,
+                    &mut events_stack,
+                    reader,
+                    font_scale,
+                    px_scale,
+                    defs,
+                    images,
+                    emitter,
+                )?;
+                if text_options.glyph_text && glyph_text.is_none() && GlyphText::starts(&element)? {
+                    glyph_text = Some(GlyphText::new(events_stack.len()));
+                }
+                let entry = events_stack.last_mut().unwrap();
+                if matches!(element.name().as_ref(), b"text" | b"flowRoot") {
+                    let mut layout = TextLayout::new(entry.transform, font_scale);
+                    if let Some(style) = &entry.style
+                        && let Some(positions) = &entry.positions
+                    {
+                        layout.wrap =
+                            TextWrap::from_style(style, positions, defs, layout.pt_per_px());
+                    }
+                    text_layout = Some(layout);
+                }
+                if let Some(layout) = &mut text_layout
+                    && element.name().as_ref() == b"textPath"
+                {
+                    layout.begin_path(TextPath::from_element(&element, defs)?);
+                }
+                if let Some(layout) = &text_layout
+                    && let Some(positions) = &mut entry.positions
+                {
+                    positions.first_char = layout.len();
+                    positions.first_glyph = layout.glyph_count();
+                }
             }
             Event::Text(text_content) => {
-                if let Some(parent) = events_stack
-                    .iter()
-                    .rev()
-                    .find(|i| i.name == b"text" || i.name == b"tspan")
-                {
-                    if let Some(positions) = &parent.positions
-                        && !positions.is_empty()
+                char_data.push_str(&text_content.xml_content()?);
+            }
+            Event::CData(cdata) => {
+                char_data.push_str(&cdata.xml_content()?);
+            }
+            Event::GeneralRef(reference) => {
+                if let Some(ch) = reference.resolve_char_ref()? {
+                    char_data.push(ch);
+                } else {
+                    let name = reference.decode()?;
+                    match resolve_predefined_entity(&name)
+                        .or(entities.get(name.as_ref()).map(String::as_str))
                     {
-                        if parent.name == b"text" {
-                            gen_content(
-                                apply_transform(
-                                    positions[0].clone(),
-                                    &events_stack.last().unwrap().transform,
-                                ),
-                                &parent.style,
-                                str::from_utf8(text_content.as_ref())?,
-                            );
-                        }
-                        if parent.name == b"tspan" {
-                            if positions.len() > 1 {
-                                for (ch, pos) in text_content.as_ref().iter().zip(positions.iter())
-                                {
-                                    if *ch == b' ' {
-                                        continue;
-                                    }
-                                    gen_content(
-                                        apply_transform(
-                                            pos.clone(),
-                                            &events_stack.last().unwrap().transform,
-                                        ),
-                                        &parent.style,
-                                        str::from_utf8(&[*ch])?,
-                                    );
-                                }
-                            } else {
-                                gen_content(
-                                    apply_transform(
-                                        positions[0].clone(),
-                                        &events_stack.last().unwrap().transform,
-                                    ),
-                                    &parent.style,
-                                    str::from_utf8(text_content.as_ref())?,
-                                );
-                            }
+                        Some(value) => char_data.push_str(value),
+                        None => {
+                            warn!("Unknown entity &{};", name);
+                            char_data.push_str(&format!("&{};", name));
                         }
-                    } else {
-                        bail!("No positions found for text!");
                     }
-                } else {
-                    return Err(anyhow::anyhow!(
-                        "Can't find parent for text {:?}",
-                        text_content
-                    ));
                 }
             }
+            Event::Comment(comment) => {
+                if let Some(glyphs) = &mut glyph_text {
+                    glyphs.comment(&comment.decode()?);
+                }
+            }
+            Event::DocType(doctype) => {
+                entities = parse_entity_declarations(&doctype.decode()?);
+            }
             Event::Empty(element) => {
-                process_element(&element, &mut events_stack, reader, font_scale, px_scale)?;
+                if !is_rendered(&element)? {
+                    continue;
+                }
+                if draws_over_text(element.name().as_ref()) {
+                    text_writer.flush(text_options);
+                }
+                if let Some(glyphs) = &mut glyph_text
+                    && element.name().as_ref() == b"use"
+                {
+                    glyphs.glyph(&element, &events_stack.last().unwrap().transform)?;
+                }
+                let depth = events_stack.len();
+                process_element(
+                    &element,
+                    &mut events_stack,
+                    reader,
+                    font_scale,
+                    px_scale,
+                    defs,
+                    images,
+                    emitter,
+                )?;
+                // there is no end event to pop the entry of an empty element
+                events_stack.truncate(depth);
             }
             _ => {
                 debug!("Unhandled event: {:?}", event);
@@ -746,22 +1490,51 @@ fn convert(
     transform: &Transform,
     font_scale: f64,
     px_scale: f64,
+    text_options: &TextOptions,
+    defs: &Definitions,
+    images: &ImageOptions,
 ) -> Result<()> {
-    handle_event(reader, transform, font_scale, px_scale)
+    handle_event(
+        reader,
+        transform,
+        font_scale,
+        px_scale,
+        text_options,
+        defs,
+        images,
+    )
 }
 
 fn main() -> Result<()> {
     env_logger::init();
     let args = Args::parse();
+    let mut font_map = FontMap::default();
+    if let Some(path) = &args.font_map_file {
+        font_map.load_file(path)?;
+    }
+    for entry in &args.font_map {
+        font_map.insert_entry(entry)?;
+    }
+    let text_options = TextOptions {
+        font_map,
+        mode: args.text_mode,
+        latex_math: !args.no_latex_math,
+        merge: args.merge_text,
+        glyph_text: args.glyph_text,
+        backend: args.backend,
+    };
     let mut input = String::new();
     io::stdin().read_to_string(&mut input)?;
+    let defs = Definitions::collect(&input)?;
     let mut reader = Reader::from_str(&input);
-    reader.config_mut().trim_text(true);
     convert(
         &mut reader,
         &Transform::new(args.scale, 0.0, 0.0, -args.scale, 0.0, 0.0),
         // &Transform::default(),
         args.font_scale,
         args.px_scale,
+        &text_options,
+        &defs,
+        &ImageOptions::new(args.image_dir, args.image_base),
     )
 }
@@ -0,0 +1,331 @@
+use std::f64::consts::PI;
+
+use anyhow::Result;
+use log::{debug, warn};
+use quick_xml::events::BytesStart;
+use svgtypes::{SimplePathSegment, Transform};
+
+use crate::{
+    SvgStyle, apply_transform,
+    clip::is_convex,
+    defs::{Definitions, MarkerDef, MarkerShape, Orient, property},
+    emit::Emitter,
+    geometry::path_outline,
+    parse_size, transform_multiply,
+};
+
+/// The markers of a path: recognized arrowheads at its ends become CeTZ marks on
+/// the first and last segment, anything else is drawn as a copy of the marker's
+/// content at each vertex.
+#[derive(Debug, Default)]
+pub struct PathMarkers {
+    start: Option<String>,
+    end: Option<String>,
+    // marker content with the transform that places it in the output, and the
+    // scale of its units in user space
+    instances: Vec<(MarkerDef, Transform, f64)>,
+    context_fill: Option<String>,
+    context_stroke: Option<String>,
+    // stroke widths in user units map to points with this factor
+    pt_per_unit: f64,
+}
+
+/// A vertex of a path, with the directions of the segments in and out of it
+struct Vertex {
+    point: (f64, f64),
+    angle_in: Option<f64>,
+    angle_out: Option<f64>,
+}
+
+impl PathMarkers {
+    /// The `marker-start`, `marker-mid` and `marker-end` an element sets, directly
+    /// or through the `marker` shorthand
+    pub fn properties(element: &BytesStart) -> Result<[Option<String>; 3]> {
+        let shorthand = property(element, "marker")?;
+        Ok([
+            property(element, "marker-start")?.or_else(|| shorthand.clone()),
+            property(element, "marker-mid")?.or_else(|| shorthand.clone()),
+            property(element, "marker-end")?.or(shorthand),
+        ])
+    }
+
+    /// Resolves the markers of a `path`, whose user space maps to the output
+    /// through `transform`, falling back to the `inherited` marker properties.
+    /// Without `marks` every marker is drawn as content.
+    #[allow(clippy::too_many_arguments)]
+    pub fn resolve(
+        element: &BytesStart,
+        inherited: [Option<String>; 3],
+        segments: &[SimplePathSegment],
+        transform: &Transform,
+        style: Option<&SvgStyle>,
+        defs: &Definitions,
+        px_scale: f64,
+        marks: bool,
+    ) -> Result<PathMarkers> {
+        let mut markers = PathMarkers {
+            context_fill: style.and_then(|s| s.fill.clone()),
+            context_stroke: style.and_then(|s| s.stroke.clone()),
+            pt_per_unit: px_scale * transform.a,
+            ..Default::default()
+        };
+        let mut referenced = Vec::new();
+        let own = Self::properties(element)?;
+        for (own, inherited) in own.into_iter().zip(inherited) {
+            let href = own.or(inherited).filter(|h| h != "none");
+            let marker = href.as_ref().and_then(|href| {
+                let marker = defs.marker(href);
+                if marker.is_none() {
+                    warn!("Unknown marker {}", href);
+                }
+                marker
+            });
+            referenced.push(marker);
+        }
+        if referenced.iter().all(Option::is_none) {
+            return Ok(markers);
+        }
+        let stroke_width = match property(element, "stroke-width")? {
+            Some(width) => parse_size(&width, 1.0)?,
+            None => 1.0,
+        };
+        let vertices = vertices(segments);
+        let Some(last) = vertices.len().checked_sub(1) else {
+            return Ok(markers);
+        };
+        for (i, vertex) in vertices.iter().enumerate() {
+            let (marker, position) = match i {
+                0 => (referenced[0], Position::Start),
+                _ if i == last => (referenced[2], Position::End),
+                _ => (referenced[1], Position::Mid),
+            };
+            let Some(marker) = marker else {
+                continue;
+            };
+            if marks
+                && position != Position::Mid
+                && let Some(mark) = cetz_mark(marker, position, stroke_width, transform, &markers)
+            {
+                match position {
+                    Position::Start => markers.start = Some(mark),
+                    _ => markers.end = Some(mark),
+                }
+                continue;
+            }
+            let angle = match marker.orient {
+                Orient::Angle(degrees) => degrees.to_radians(),
+                Orient::AutoStartReverse if position == Position::Start => vertex.angle() + PI,
+                _ => vertex.angle(),
+            };
+            let units = marker_units(marker, stroke_width);
+            let placement = transform_multiply(
+                transform,
+                &transform_multiply(
+                    &Transform::new(
+                        angle.cos(),
+                        angle.sin(),
+                        -angle.sin(),
+                        angle.cos(),
+                        vertex.point.0,
+                        vertex.point.1,
+                    ),
+                    &units,
+                ),
+            );
+            markers.instances.push((marker.clone(), placement, units.a));
+        }
+        Ok(markers)
+    }
+
+    /// The `mark` argument for a drawn segment, given whether it's the first and
+    /// the last of the path
+    pub fn mark(&self, first: bool, last: bool) -> Option<String> {
+        let mut marks = Vec::new();
+        if first && let Some(start) = &self.start {
+            marks.push(format!("start: {}", start));
+        }
+        if last && let Some(end) = &self.end {
+            marks.push(format!("end: {}", end));
+        }
+        (!marks.is_empty()).then(|| format!("mark: ({}), ", marks.join(", ")))
+    }
+
+    /// Draws the markers that didn't become marks, after the path itself.
+    pub fn emit(&self, emitter: &dyn Emitter) {
+        for (marker, placement, scale) in &self.instances {
+            for shape in &marker.shapes {
+                let style = SvgStyle {
+                    fill: self.paint(shape.fill.as_ref()),
+                    stroke: self.paint(shape.stroke.as_ref()),
+                    stroke_width: shape.stroke_width.map(|w| w * scale * self.pt_per_unit),
+                    ..Default::default()
+                };
+                for line in &shape.lines.0 {
+                    let points = line
+                        .iter()
+                        .map(|p| apply_transform(*p, placement))
+                        .collect();
+                    emitter.polylines(&[points], shape.closed, &style);
+                }
+            }
+        }
+    }
+
+    /// Marker paint, with references to the context's paint resolved
+    fn paint(&self, paint: Option<&String>) -> Option<String> {
+        match paint.map(String::as_str) {
+            Some("context-fill") => self.context_fill.clone(),
+            Some("context-stroke") => self.context_stroke.clone(),
+            paint => paint.map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Position {
+    Start,
+    Mid,
+    End,
+}
+
+impl Vertex {
+    /// Marker direction: the bisector of both segments where there are two
+    fn angle(&self) -> f64 {
+        match (self.angle_in, self.angle_out) {
+            (Some(a), Some(b)) => {
+                let mut diff = b - a;
+                if diff > PI {
+                    diff -= 2.0 * PI;
+                } else if diff < -PI {
+                    diff += 2.0 * PI;
+                }
+                a + diff / 2.0
+            }
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => 0.0,
+        }
+    }
+}
+
+/// The vertices of a path in its user space, subpath starts and segment ends
+fn vertices(segments: &[SimplePathSegment]) -> Vec<Vertex> {
+    let mut vertices = Vec::new();
+    for subpath in path_outline(segments, &Transform::default()) {
+        let segments = &subpath.segments;
+        vertices.push(Vertex {
+            point: segments[0].start(),
+            angle_in: None,
+            angle_out: segments[0].start_angle(),
+        });
+        for (i, segment) in segments.iter().enumerate() {
+            vertices.push(Vertex {
+                point: segment.end(),
+                angle_in: segment.end_angle(),
+                angle_out: segments.get(i + 1).and_then(|s| s.start_angle()),
+            });
+        }
+    }
+    vertices
+}
+
+/// Maps marker content to the marker's position at the origin: the scale of
+/// `markerUnits` and `viewBox`, with the reference point moved to the origin.
+fn marker_units(marker: &MarkerDef, stroke_width: f64) -> Transform {
+    let units = if marker.stroke_units {
+        stroke_width
+    } else {
+        1.0
+    };
+    // preserveAspectRatio is the default xMidYMid meet
+    let view_scale = match marker.view_box {
+        Some([_, _, w, h]) if w > 0.0 && h > 0.0 => (marker.width / w).min(marker.height / h),
+        _ => 1.0,
+    };
+    let scale = units * view_scale;
+    Transform::new(
+        scale,
+        0.0,
+        0.0,
+        scale,
+        -marker.ref_x * scale,
+        -marker.ref_y * scale,
+    )
+}
+
+/// The CeTZ mark for a marker that is a common arrowhead shape
+fn cetz_mark(
+    marker: &MarkerDef,
+    position: Position,
+    stroke_width: f64,
+    transform: &Transform,
+    context: &PathMarkers,
+) -> Option<String> {
+    if matches!(marker.orient, Orient::Angle(_)) {
+        return None;
+    }
+    let [shape] = marker.shapes.as_slice() else {
+        return None;
+    };
+    let [points] = shape.lines.0.as_slice() else {
+        return None;
+    };
+    let mut points = points.clone();
+    points.dedup();
+    if points.len() > 1 && points.first() == points.last() {
+        points.pop();
+    }
+    let symbol = mark_symbol(shape, &points)?;
+    // the side the tip points to, on the x axis of the marker
+    let centroid = points.iter().map(|p| p.0).sum::<f64>() / points.len() as f64;
+    let tip = points
+        .iter()
+        .map(|p| p.0 - centroid)
+        .fold(0.0, |tip: f64, x| if x.abs() > tip.abs() { x } else { tip });
+    let forward = tip >= 0.0;
+    // marks point away from the path at both ends
+    let reverse = match (position, marker.orient) {
+        (Position::End, _) => !forward,
+        (_, Orient::AutoStartReverse) => !forward,
+        _ => forward,
+    };
+    let bounds = shape.lines.bounds()?;
+    let scale = marker_units(marker, stroke_width).a
+        * (transform.a * transform.d - transform.b * transform.c)
+            .abs()
+            .sqrt();
+    let mut mark = format!(
+        "(symbol: \"{}\", length: {:.3}, width: {:.3}",
+        symbol,
+        bounds[2] * scale,
+        bounds[3] * scale
+    );
+    match context.paint(shape.fill.as_ref()).as_deref() {
+        Some("none") | None => mark.push_str(", fill: none"),
+        Some(fill) => mark.push_str(&format!(", fill: {}", fill)),
+    }
+    if reverse && !matches!(symbol, "o" | "[]" | "<>") {
+        mark.push_str(", reverse: true");
+    }
+    mark.push(')');
+    debug!("marker as mark {}", mark);
+    Some(mark)
+}
+
+fn mark_symbol(shape: &MarkerShape, points: &[(f64, f64)]) -> Option<&'static str> {
+    let filled = shape.fill.as_deref().is_some_and(|f| f != "none");
+    match (shape.kind.as_slice(), points.len()) {
+        (b"circle" | b"ellipse", _) => Some("o"),
+        (_, 3) if shape.closed || filled => Some(">"),
+        // an open chevron
+        (_, 3) => Some("straight"),
+        (_, 4) if !is_convex(points) => Some("stealth"),
+        (_, 4) => {
+            let axis_aligned = points
+                .iter()
+                .zip(points.iter().cycle().skip(1))
+                .all(|(a, b)| a.0 == b.0 || a.1 == b.1);
+            Some(if axis_aligned { "[]" } else { "<>" })
+        }
+        _ => None,
+    }
+}
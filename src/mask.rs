@@ -0,0 +1,290 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use log::{debug, warn};
+use quick_xml::events::BytesStart;
+use svgtypes::Color;
+
+use crate::{
+    SvgStyle,
+    clip::is_convex,
+    defs::{Definitions, GradientDef, property},
+};
+
+/// What a `mask` does to the paint of the elements it applies to. Only masks
+/// whose shapes all have the same uniform or gradient fill can be represented.
+#[derive(Debug, Clone)]
+pub enum Mask {
+    Opacity(f64),
+    /// Opacity varying along a gradient, one value per stop
+    Gradient {
+        gradient: GradientDef,
+        alphas: Vec<f64>,
+    },
+}
+
+impl Mask {
+    /// Resolves the `mask` of an element, `bbox` is its bounding box in user space
+    /// where it is known.
+    pub fn from_element(
+        element: &BytesStart,
+        defs: &Definitions,
+        bbox: Option<[f64; 4]>,
+    ) -> Result<Option<Mask>> {
+        let Some(href) = property(element, "mask")?.filter(|h| h != "none") else {
+            return Ok(None);
+        };
+        let Some(def) = defs.mask(&href) else {
+            warn!("Unknown mask {}", href);
+            return Ok(None);
+        };
+        // an empty mask hides everything
+        let Some(paint) = def.paints.first() else {
+            return Ok(Some(Mask::Opacity(0.0)));
+        };
+        if def
+            .paints
+            .iter()
+            .any(|p| p.fill != paint.fill || p.opacity != paint.opacity)
+        {
+            warn!(
+                "Mask {} has shapes with different paints, which can't be represented; ignored",
+                href
+            );
+            return Ok(None);
+        }
+        // the mask is applied to the whole paint, which is only right where it
+        // covers the whole element
+        if let Some(bbox) = bbox
+            && !def
+                .paints
+                .iter()
+                .any(|p| p.polygon.as_deref().is_some_and(|poly| covers(poly, bbox)))
+        {
+            warn!(
+                "Mask {} covers only part of the element, which can't be represented; applied to all of it",
+                href
+            );
+        }
+        let value = |color: Color, opacity: f64| {
+            let alpha = color.alpha as f64 / 255.0 * opacity;
+            if def.alpha {
+                alpha
+            } else {
+                luminance(color) * alpha
+            }
+        };
+        if paint.fill.starts_with("url(") {
+            let Some(mut gradient) = defs.gradient(&paint.fill) else {
+                warn!("Mask {} uses unknown paint {}; ignored", href, paint.fill);
+                return Ok(None);
+            };
+            // mask content is in the user space of the masked element
+            if let Some(bounds) = paint.bounds {
+                gradient = gradient.to_user_space(bounds);
+            }
+            let alphas: Vec<f64> = gradient
+                .stops
+                .iter()
+                .map(|s| value(s.color, s.opacity * paint.opacity))
+                .collect();
+            return Ok(match alphas.as_slice() {
+                [] => {
+                    warn!("Mask {} uses a gradient without stops; ignored", href);
+                    None
+                }
+                [alpha] => Some(Mask::Opacity(*alpha)),
+                _ => Some(Mask::Gradient { gradient, alphas }),
+            });
+        }
+        match Color::from_str(&paint.fill) {
+            Ok(color) => Ok(Some(Mask::Opacity(value(color, paint.opacity)))),
+            Err(_) => {
+                warn!(
+                    "Mask {} with paint {} can't be represented; ignored",
+                    href, paint.fill
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    /// The combined effect of nested masks
+    pub fn combine(self, inner: Mask) -> Mask {
+        match (self, inner) {
+            (Mask::Opacity(a), Mask::Opacity(b)) => Mask::Opacity(a * b),
+            (Mask::Opacity(a), Mask::Gradient { gradient, alphas })
+            | (Mask::Gradient { gradient, alphas }, Mask::Opacity(a)) => Mask::Gradient {
+                gradient,
+                alphas: alphas.iter().map(|alpha| alpha * a).collect(),
+            },
+            (outer, Mask::Gradient { .. }) => {
+                warn!("Nested gradient masks can't be represented, only the outer one is used");
+                outer
+            }
+        }
+    }
+
+    /// Applies the mask to the fill and stroke of a shape, `bbox` is its bounding
+    /// box in user space.
+    pub fn apply(&self, style: &mut SvgStyle, bbox: Option<[f64; 4]>) {
+        style.fill = style.fill.take().map(|p| self.paint(&p, bbox));
+        style.stroke = style.stroke.take().map(|p| self.paint(&p, bbox));
+    }
+
+    fn paint(&self, paint: &str, bbox: Option<[f64; 4]>) -> String {
+        // paints of the output may already be wrapped like rgb("#ff0000")
+        let color = paint
+            .strip_prefix("rgb(\"")
+            .and_then(|p| p.strip_suffix("\")"))
+            .unwrap_or(paint);
+        if color == "none" {
+            return paint.to_string();
+        }
+        let Ok(color) = Color::from_str(color) else {
+            warn!("Can't apply a mask to paint {}", paint);
+            return paint.to_string();
+        };
+        match self {
+            Mask::Opacity(alpha) => rgba(color, *alpha),
+            Mask::Gradient { gradient, alphas } => gradient_paint(color, gradient, alphas, bbox),
+        }
+    }
+}
+
+/// Whether a polygon contains the rectangle `[x, y, width, height]`, which is
+/// only checked for convex ones
+fn covers(polygon: &[(f64, f64)], [x, y, w, h]: [f64; 4]) -> bool {
+    if !is_convex(polygon) {
+        return false;
+    }
+    let n = polygon.len();
+    let edge = |i: usize| (polygon[i], polygon[(i + 1) % n]);
+    let orientation = (0..n)
+        .map(|i| {
+            let (a, b) = edge(i);
+            a.0 * b.1 - a.1 * b.0
+        })
+        .sum::<f64>()
+        .signum();
+    // corners on the outline count as inside
+    let tolerance = 1e-6 * (w * w + h * h).max(1.0);
+    [(x, y), (x + w, y), (x + w, y + h), (x, y + h)]
+        .iter()
+        .all(|p| {
+            (0..n).all(|i| {
+                let (a, b) = edge(i);
+                orientation * ((b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0)) >= -tolerance
+            })
+        })
+}
+
+/// A Typst gradient of `color` with the mask's opacities. Typst gradients are
+/// relative to the shape's bounding box, like those in `objectBoundingBox` units.
+fn gradient_paint(
+    color: Color,
+    gradient: &GradientDef,
+    alphas: &[f64],
+    bbox: Option<[f64; 4]>,
+) -> String {
+    let (origin, size) = match bbox {
+        Some([x, y, w, h]) if w > 0.0 && h > 0.0 => ((x, y), (w, h)),
+        _ if gradient.user_space => {
+            warn!("Gradient mask in user space without a bounding box, using its mean opacity");
+            let mean = alphas.iter().sum::<f64>() / alphas.len() as f64;
+            return rgba(color, mean);
+        }
+        _ => ((0.0, 0.0), (1.0, 1.0)),
+    };
+    // a point of the gradient relative to the bounding box, and scaled to its size
+    let point = |x: f64, y: f64| {
+        if gradient.user_space {
+            (x - origin.0, y - origin.1)
+        } else {
+            (x * size.0, y * size.1)
+        }
+    };
+    if gradient.radial {
+        let (cx, cy) = point(gradient.coord("cx"), gradient.coord("cy"));
+        let r = if gradient.user_space {
+            gradient.coord("r") / size.0
+        } else {
+            gradient.coord("r")
+        };
+        let stops: Vec<String> = gradient
+            .stops
+            .iter()
+            .zip(alphas)
+            .map(|(s, alpha)| format!("({}, {:.1}%)", rgba(color, *alpha), s.offset * 100.0))
+            .collect();
+        return format!(
+            "gradient.radial({}, center: ({:.1}%, {:.1}%), radius: {:.1}%)",
+            stops.join(", "),
+            cx / size.0 * 100.0,
+            cy / size.1 * 100.0,
+            r * 100.0
+        );
+    }
+    let p1 = point(gradient.coord("x1"), gradient.coord("y1"));
+    let p2 = point(gradient.coord("x2"), gradient.coord("y2"));
+    let (dx, dy) = (p2.0 - p1.0, p2.1 - p1.1);
+    let length = dx.hypot(dy);
+    if length == 0.0 {
+        return rgba(color, *alphas.last().unwrap());
+    }
+    let dir = (dx / length, dy / length);
+    // Typst spreads the gradient over the projection of the whole box
+    let project = |p: (f64, f64)| p.0 * dir.0 + p.1 * dir.1;
+    let corners = [(0.0, 0.0), (size.0, 0.0), (0.0, size.1), size].map(project);
+    let min = corners.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = corners.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let start = project(p1);
+    let mut stops: Vec<(f64, f64)> = Vec::new();
+    for (s, alpha) in gradient.stops.iter().zip(alphas) {
+        // stops may not go backwards
+        let position = (start + s.offset * length - min) / (max - min);
+        let position = stops.last().map_or(position, |last| position.max(last.0));
+        stops.push((position, *alpha));
+    }
+    // the opacity where a stop outside of the box would have been cut off
+    let alpha_at = |position: f64| {
+        let after = stops.iter().position(|s| s.0 >= position);
+        match after {
+            Some(0) => stops[0].1,
+            Some(i) => {
+                let (a, b) = (stops[i - 1], stops[i]);
+                a.1 + (b.1 - a.1) * (position - a.0) / (b.0 - a.0)
+            }
+            None => stops.last().unwrap().1,
+        }
+    };
+    let mut visible = vec![(0.0, alpha_at(0.0))];
+    visible.extend(stops.iter().filter(|s| s.0 > 0.0 && s.0 < 1.0));
+    visible.push((1.0, alpha_at(1.0)));
+    let stops: Vec<String> = visible
+        .iter()
+        .map(|(position, alpha)| format!("({}, {:.1}%)", rgba(color, *alpha), position * 100.0))
+        .collect();
+    let angle = dy.atan2(dx).to_degrees();
+    debug!("gradient mask at {:.1}deg", angle);
+    format!(
+        "gradient.linear({}, angle: {:.1}deg)",
+        stops.join(", "),
+        angle
+    )
+}
+
+fn rgba(color: Color, alpha: f64) -> String {
+    format!(
+        "rgb({}, {}, {}, {})",
+        color.red,
+        color.green,
+        color.blue,
+        (color.alpha as f64 * alpha.clamp(0.0, 1.0)).round() as u8
+    )
+}
+
+/// Relative luminance of a color, as used by luminance masks
+fn luminance(color: Color) -> f64 {
+    (0.2125 * color.red as f64 + 0.7154 * color.green as f64 + 0.0721 * color.blue as f64) / 255.0
+}
@@ -0,0 +1,1201 @@
+use std::str::FromStr;
+
+use anyhow::Result;
+use log::{debug, warn};
+use quick_xml::{
+    Reader,
+    events::{BytesStart, Event},
+};
+use svgtypes::Transform;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{
+    BaselineShift, LineHeight, Spacing, SvgStyle, apply_transform,
+    defs::Definitions,
+    emit::{Backend, Emitter},
+    fonts::{FontMap, format_font_array},
+    geometry::{Polylines, shape_bounds},
+    latex::{Segment, split_math, to_typst_math},
+    parse_size, parse_size_list, transform_multiply,
+    typst::{escape_markup, string_literal},
+};
+
+/// How character data is turned into typst content
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextMode {
+    /// Escaped typst markup, showing the text literally
+    #[default]
+    Markup,
+    /// A typst string literal
+    Raw,
+    /// Unescaped markup, for labels that contain deliberate typst code
+    Passthrough,
+}
+
+/// Settings for converting text content
+#[derive(Debug, Default, Clone)]
+pub struct TextOptions {
+    pub font_map: FontMap,
+    pub mode: TextMode,
+    /// Convert LaTeX math in markup text into typst math
+    pub latex_math: bool,
+    pub merge: TextMerge,
+    /// Recover the text of matplotlib's glyph references
+    pub glyph_text: bool,
+    /// What the drawing is written as, shapes as well as text
+    pub backend: Backend,
+}
+
+/// Merging of separately positioned text, as produced by pdf converters that place
+/// every glyph on its own
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextMerge {
+    /// Keep every positioned chunk
+    #[default]
+    None,
+    /// Join glyphs without a gap between them into words
+    Words,
+    /// Join words on the same baseline into lines, inserting spaces at gaps
+    Lines,
+}
+
+/// The transform of a text run as typst sees it: the baseline rotation in degrees
+/// (counter-clockwise), the scale along and across the baseline and the skew of the
+/// glyphs' vertical axis in degrees.
+#[derive(Debug, Clone, Copy)]
+pub struct TextTransform {
+    pub angle: f64,
+    pub scale_x: f64,
+    pub scale_y: f64,
+    pub skew: f64,
+}
+
+impl TextTransform {
+    pub fn from_transform(t: &Transform) -> Self {
+        // the baseline direction, and the glyphs' up direction as svg's y axis points down
+        let (ux, uy) = (t.a, t.b);
+        let (vx, vy) = (-t.c, -t.d);
+        let angle = uy.atan2(ux);
+        let (sin, cos) = angle.sin_cos();
+        // the up direction in the frame of the baseline
+        let along = vx * cos + vy * sin;
+        let across = -vx * sin + vy * cos;
+        let skew = if across != 0.0 {
+            (along / across).atan()
+        } else {
+            0.0
+        };
+        TextTransform {
+            angle: angle.to_degrees(),
+            scale_x: ux.hypot(uy),
+            scale_y: across,
+            skew: skew.to_degrees(),
+        }
+    }
+
+    /// Scale of font sizes, taken across the baseline so that stretched text keeps
+    /// its height.
+    pub fn font_scale(&self) -> f64 {
+        self.scale_y.abs()
+    }
+}
+
+/// Positioning attributes of a `text` or `tspan`. The lists apply to the element's
+/// characters by index, including those of nested elements.
+#[derive(Debug, Default, Clone)]
+pub struct TextPositions {
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+    pub dx: Vec<f64>,
+    pub dy: Vec<f64>,
+    pub rotate: Option<Vec<f64>>,
+    /// `sodipodi:role="line"`, the element starts a new line
+    pub new_line: bool,
+    /// `textLength`, the advance the content is adjusted to
+    pub text_length: Option<f64>,
+    pub length_adjust: LengthAdjust,
+    /// Index of the element's first character within its `text` element
+    pub first_char: usize,
+    /// Index of the element's first glyph in the text layout
+    pub first_glyph: usize,
+}
+
+impl TextPositions {
+    /// Sets a positioning attribute, returns false for any other attribute.
+    pub fn set_attribute(&mut self, key: &[u8], value: &str) -> Result<bool> {
+        match key {
+            b"x" => self.x = parse_size_list(value, 1.0)?,
+            b"y" => self.y = parse_size_list(value, 1.0)?,
+            b"dx" => self.dx = parse_size_list(value, 1.0)?,
+            b"dy" => self.dy = parse_size_list(value, 1.0)?,
+            b"rotate" => self.rotate = Some(parse_size_list(value, 1.0)?),
+            b"sodipodi:role" => self.new_line = value == "line",
+            b"textLength" => self.text_length = Some(parse_size(value, 1.0)?),
+            b"lengthAdjust" => {
+                self.length_adjust = match value {
+                    "spacingAndGlyphs" => LengthAdjust::SpacingAndGlyphs,
+                    _ => LengthAdjust::Spacing,
+                }
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+/// How `textLength` is achieved
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LengthAdjust {
+    /// Extra space between the characters
+    #[default]
+    Spacing,
+    /// Stretching the glyphs along with their advances
+    SpacingAndGlyphs,
+}
+
+/// White space processing of text content, from CSS `white-space` or `xml:space`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WhiteSpace {
+    /// Sequences of white space collapse into one space, and are removed at the
+    /// start and end of lines
+    #[default]
+    Collapse,
+    /// Every white space character is shown as a space
+    Preserve,
+}
+
+impl WhiteSpace {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            // line breaks are not rendered in svg text, pre-line only differs in those
+            "normal" | "nowrap" | "pre-line" | "collapse" | "preserve-breaks" | "default" => {
+                Some(WhiteSpace::Collapse)
+            }
+            "pre" | "pre-wrap" | "break-spaces" | "preserve" | "preserve-spaces" => {
+                Some(WhiteSpace::Preserve)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Glyph {
+    text: String,
+    // a collapsed space, removed at the start and end of a chunk
+    collapsible: bool,
+    // preserved white space, shown as non-breaking spaces
+    preserved: bool,
+    x: Option<f64>,
+    y: Option<f64>,
+    dx: f64,
+    dy: f64,
+    rotate: f64,
+    new_line: bool,
+    style: usize,
+    // index of the text path the glyph is placed on
+    path: Option<usize>,
+}
+
+/// The path of a `textPath` element, in the coordinate system of its text
+#[derive(Debug)]
+pub struct TextPath {
+    lines: Polylines,
+    start_offset: f64,
+}
+
+impl TextPath {
+    pub fn from_element(element: &BytesStart, defs: &Definitions) -> Result<Self> {
+        let mut href = None;
+        let mut start_offset = "0".to_string();
+        let mut right_side = false;
+        for attr in element.attributes() {
+            let attr = attr?;
+            let value = attr.unescape_value()?;
+            match attr.key.as_ref() {
+                b"href" | b"xlink:href" => href = Some(value.to_string()),
+                b"startOffset" => start_offset = value.to_string(),
+                b"side" => right_side = value == "right",
+                b"method" if value == "stretch" => {
+                    debug!("Unsupported textPath method, glyphs are not stretched")
+                }
+                _ => {}
+            }
+        }
+        let mut lines = match href.as_deref().and_then(|href| defs.path(href)) {
+            Some(path) => path.polylines()?,
+            None => {
+                warn!("Unresolved textPath reference {:?}", href);
+                Polylines::default()
+            }
+        };
+        // the right side of a path is the left side of the reversed path
+        if right_side {
+            lines = lines.reversed();
+        }
+        let start_offset = match start_offset.strip_suffix('%') {
+            Some(percent) => f64::from_str(percent)? / 100.0 * lines.length(),
+            None => parse_size(&start_offset, 1.0)?,
+        };
+        Ok(TextPath {
+            lines,
+            start_offset,
+        })
+    }
+}
+
+/// The area text is wrapped in, from a `flowRegion`, `shape-inside` or `inline-size`
+#[derive(Debug, Clone, Copy)]
+pub struct TextWrap {
+    /// The top left corner
+    pub pos: (f64, f64),
+    pub width: f64,
+}
+
+impl TextWrap {
+    /// The wrapping area of an svg 2 `text` element, if it has one.
+    pub fn from_style(
+        style: &SvgStyle,
+        positions: &TextPositions,
+        defs: &Definitions,
+        pt_per_px: f64,
+    ) -> Option<Self> {
+        if let Some(shape) = &style.shape_inside {
+            let Some([x, y, width, height]) = defs.bounds(shape) else {
+                warn!("Unresolved shape-inside {}", shape);
+                return None;
+            };
+            let padding = style
+                .shape_padding
+                .unwrap_or(0.0)
+                .min(width / 2.0)
+                .min(height / 2.0);
+            return Some(TextWrap {
+                pos: (x + padding, y + padding),
+                width: width - 2.0 * padding,
+            });
+        }
+        let width = style.inline_size?;
+        // the position is that of the first baseline, typst's lines start at the
+        // cap height of the font
+        let font_size = style.font_size.map_or(16.0, |size| size / pt_per_px);
+        let (x, y) = (
+            positions.x.first().copied().unwrap_or(0.0),
+            positions.y.first().copied().unwrap_or(0.0),
+        );
+        Some(TextWrap {
+            pos: (x, y - 0.7 * font_size),
+            width,
+        })
+    }
+}
+
+/// Reads the rest of a `flowRegion` element, returning the area of its first shape.
+pub fn read_flow_region(reader: &mut Reader<&[u8]>) -> Result<Option<TextWrap>> {
+    let mut wrap = None;
+    let mut depth = 0;
+    loop {
+        let event = reader.read_event()?;
+        let element = match &event {
+            Event::Start(element) => {
+                depth += 1;
+                element
+            }
+            Event::Empty(element) => element,
+            Event::End(_) if depth == 0 => break,
+            Event::End(_) => {
+                depth -= 1;
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+        if wrap.is_none()
+            && let Some([x, y, width, _]) = shape_bounds(element)?
+        {
+            wrap = Some(TextWrap { pos: (x, y), width });
+        }
+    }
+    Ok(wrap)
+}
+
+/// Block flow direction of a `text` element
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WritingMode {
+    #[default]
+    Horizontal,
+    /// Lines run top to bottom, with upright east asian characters and other
+    /// characters rotated clockwise
+    Vertical,
+}
+
+impl WritingMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "horizontal-tb" | "lr" | "lr-tb" | "rl" | "rl-tb" => Some(WritingMode::Horizontal),
+            "vertical-rl" | "vertical-lr" | "sideways-rl" | "sideways-lr" | "tb" | "tb-rl" => {
+                Some(WritingMode::Vertical)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether a character stays upright in vertical text, as east asian scripts do
+fn is_upright(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11ff}'
+        | '\u{2e80}'..='\u{a4cf}'
+        | '\u{ac00}'..='\u{d7a3}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{fe30}'..='\u{fe4f}'
+        | '\u{ff00}'..='\u{ff60}'
+        | '\u{ffe0}'..='\u{ffe6}'
+        | '\u{20000}'..='\u{3fffd}')
+}
+
+/// A part of a text chunk with a single style and baseline
+#[derive(Debug, Clone)]
+struct Run {
+    glyphs: Vec<Glyph>,
+    // horizontal space before the run, in svg units
+    space: f64,
+    baseline: f64,
+    style: Option<SvgStyle>,
+    // the run starts a paragraph of flowed text
+    par_break: bool,
+}
+
+/// Text chunks start at an absolute position, their content is laid out by typst.
+#[derive(Debug)]
+struct Chunk {
+    pos: (f64, f64),
+    transform: Transform,
+    pt_per_px: f64,
+    runs: Vec<Run>,
+    // `textLength` of the element whose content is exactly this chunk
+    length: Option<(f64, LengthAdjust)>,
+    // width of flowed text, which typst wraps
+    width: Option<f64>,
+    // estimated end of the content along the baseline, in svg units
+    end_x: f64,
+}
+
+/// Collects the characters of a `text` element and lays them out once it ends, so
+/// that runs of nested `tspan`s without their own position stay in one chunk.
+#[derive(Debug)]
+pub struct TextLayout {
+    transform: Transform,
+    // typst points per svg unit of the text's coordinate system
+    pt_per_px: f64,
+    styles: Vec<Option<SvgStyle>>,
+    glyphs: Vec<Glyph>,
+    // number of addressable characters, a glyph may consist of several
+    chars: usize,
+    // glyph ranges of elements with a `textLength`
+    lengths: Vec<(std::ops::Range<usize>, f64, LengthAdjust)>,
+    paths: Vec<TextPath>,
+    // the `textPath` the next glyphs are placed on
+    current_path: Option<usize>,
+    /// Wrapping of flowed text, which ignores the positions of characters
+    pub wrap: Option<TextWrap>,
+}
+
+impl TextLayout {
+    pub fn new(transform: Transform, font_scale: f64) -> Self {
+        TextLayout {
+            transform,
+            pt_per_px: font_scale * TextTransform::from_transform(&transform).font_scale(),
+            styles: Vec::new(),
+            glyphs: Vec::new(),
+            chars: 0,
+            lengths: Vec::new(),
+            paths: Vec::new(),
+            current_path: None,
+            wrap: None,
+        }
+    }
+
+    /// Typst points per svg unit of the text's coordinate system
+    pub fn pt_per_px(&self) -> f64 {
+        self.pt_per_px
+    }
+
+    pub fn begin_path(&mut self, path: TextPath) {
+        self.current_path = Some(self.paths.len());
+        self.paths.push(path);
+    }
+
+    pub fn end_path(&mut self) {
+        self.current_path = None;
+    }
+
+    /// Number of addressable characters so far, the index of the next one
+    pub fn len(&self) -> usize {
+        self.chars
+    }
+
+    /// Number of glyphs so far, the index of the next one
+    pub fn glyph_count(&self) -> usize {
+        self.glyphs.len()
+    }
+
+    /// Applies the `textLength` of an element that ends, if it has one.
+    pub fn end_element(&mut self, positions: &TextPositions) {
+        if let Some(length) = positions.text_length {
+            self.lengths.push((
+                positions.first_glyph..self.glyphs.len(),
+                length,
+                positions.length_adjust,
+            ));
+        }
+    }
+
+    /// Appends character data, positioned by the innermost element of `positions`
+    /// that has a value for a character.
+    ///
+    /// Positions are indexed by unicode code point. A grapheme cluster is laid out as
+    /// one glyph at the position of its first code point, the values for the rest
+    /// of the cluster are skipped as svg 2 specifies.
+    pub fn push_text(
+        &mut self,
+        text: &str,
+        style: &Option<SvgStyle>,
+        positions: &[&TextPositions],
+    ) {
+        if self.styles.last() != Some(style) {
+            self.styles.push(style.clone());
+        }
+        let white_space = self.styles[self.styles.len() - 1]
+            .as_ref()
+            .and_then(|s| s.white_space)
+            .unwrap_or_default();
+        let style = self.styles.len() - 1;
+        for cluster in text.graphemes(true) {
+            let is_space = cluster
+                .chars()
+                .all(|c| matches!(c, ' ' | '\t' | '\n' | '\r'));
+            let preserved = is_space && white_space == WhiteSpace::Preserve;
+            let (cluster, collapsible) = match white_space {
+                WhiteSpace::Collapse if is_space => {
+                    // removed white space is not addressable by positions
+                    if self.glyphs.last().is_none_or(|g| g.collapsible) {
+                        continue;
+                    }
+                    (" ".to_string(), true)
+                }
+                // typst collapses spaces, non-breaking ones keep their width
+                WhiteSpace::Preserve if is_space => {
+                    ("\u{a0}".repeat(cluster.chars().count()), false)
+                }
+                _ => (cluster.to_string(), false),
+            };
+            let index = self.chars;
+            self.chars += cluster.chars().count();
+            let lookup = |list: fn(&TextPositions) -> &Vec<f64>| {
+                positions
+                    .iter()
+                    .find_map(|p| list(p).get(index - p.first_char).copied())
+            };
+            // unlike the other attributes, the last angle applies to the remaining characters
+            let rotate = positions
+                .iter()
+                .find_map(|p| {
+                    let rotate = p.rotate.as_ref()?;
+                    rotate.get(index - p.first_char).or(rotate.last())
+                })
+                .copied()
+                .unwrap_or(0.0);
+            self.glyphs.push(Glyph {
+                text: cluster,
+                collapsible,
+                preserved,
+                x: lookup(|p| &p.x),
+                y: lookup(|p| &p.y),
+                dx: lookup(|p| &p.dx).unwrap_or(0.0),
+                dy: lookup(|p| &p.dy).unwrap_or(0.0),
+                rotate,
+                new_line: positions
+                    .first()
+                    .is_some_and(|p| p.new_line && p.first_char == index),
+                style,
+                path: self.current_path,
+            });
+        }
+    }
+
+    /// Distance between baselines of consecutive lines, in svg units
+    fn line_advance(&self, style: usize) -> f64 {
+        let style = self.styles[style].as_ref();
+        // 16px is the default font size of browsers
+        let font_size = style
+            .and_then(|s| s.font_size)
+            .unwrap_or(16.0 * self.pt_per_px);
+        let line_height = match style.and_then(|s| s.line_height) {
+            Some(LineHeight::Factor(factor)) => font_size * factor,
+            Some(LineHeight::Length(length)) => length,
+            None => font_size * 1.25,
+        };
+        line_height / self.pt_per_px
+    }
+
+    /// Flowed text as one chunk, with its paragraphs
+    fn wrapped_chunk(&self, wrap: TextWrap) -> Chunk {
+        let mut runs: Vec<Run> = Vec::new();
+        for glyph in &self.glyphs {
+            if glyph.collapsible && (runs.is_empty() || glyph.new_line) {
+                continue;
+            }
+            let mut glyph = glyph.clone();
+            if glyph.preserved {
+                // lines may still break at preserved spaces of flowed text
+                glyph.text = glyph.text.replacen('\u{a0}', " ", 1);
+            }
+            let style = &self.styles[glyph.style];
+            let par_break = glyph.new_line && !runs.is_empty();
+            match runs.last_mut() {
+                Some(run) if run.style == *style && !par_break => run.glyphs.push(glyph),
+                _ => runs.push(Run {
+                    glyphs: vec![glyph],
+                    space: 0.0,
+                    baseline: 0.0,
+                    style: style.clone(),
+                    par_break,
+                }),
+            }
+        }
+        Chunk {
+            pos: wrap.pos,
+            transform: self.transform,
+            pt_per_px: self.pt_per_px,
+            runs,
+            length: None,
+            width: Some(wrap.width),
+            end_x: wrap.pos.0 + wrap.width,
+        }
+    }
+
+    fn chunks(&self) -> Vec<Chunk> {
+        if let Some(wrap) = self.wrap {
+            return vec![self.wrapped_chunk(wrap)];
+        }
+        let mut chunks: Vec<Chunk> = Vec::new();
+        // indices of the first and the last visible glyph of each chunk
+        let mut ranges: Vec<Option<(usize, usize)>> = Vec::new();
+        let mut line_start = (0.0, 0.0);
+        let mut cur_y = 0.0;
+        // the current text path and the distance along it
+        let mut path_pos: Option<(usize, f64)> = None;
+        for (index, glyph) in self.glyphs.iter().enumerate() {
+            if let Some(path) = glyph.path {
+                let dist = match path_pos {
+                    Some((p, dist)) if p == path => dist,
+                    _ => self.paths[path].start_offset,
+                } + glyph.dx;
+                let style = &self.styles[glyph.style];
+                let em = style
+                    .as_ref()
+                    .and_then(|s| s.font_size)
+                    .map_or(16.0, |size| size / self.pt_per_px);
+                let advance = estimate_advance(&glyph.text) * em;
+                path_pos = Some((path, dist + advance));
+                // glyphs are centered on the path, off its ends they are not shown
+                let Some(((x, y), angle)) = self.paths[path].lines.point_at(dist + advance / 2.0)
+                else {
+                    continue;
+                };
+                let (sin, cos) = angle.sin_cos();
+                let origin = (x - cos * advance / 2.0, y - sin * advance / 2.0);
+                chunks.push(Chunk {
+                    pos: (0.0, 0.0),
+                    transform: transform_multiply(
+                        &self.transform,
+                        &Transform::new(cos, sin, -sin, cos, origin.0, origin.1),
+                    ),
+                    pt_per_px: self.pt_per_px,
+                    runs: vec![Run {
+                        glyphs: vec![glyph.clone()],
+                        space: 0.0,
+                        baseline: 0.0,
+                        style: style.clone(),
+                        par_break: false,
+                    }],
+                    length: None,
+                    width: None,
+                    end_x: advance,
+                });
+                ranges.push(None);
+                continue;
+            }
+            let (mut x, mut y) = (glyph.x, glyph.y);
+            if glyph.new_line {
+                x = x.or(Some(line_start.0));
+                y = y.or(Some(line_start.1 + self.line_advance(glyph.style)));
+            }
+            if x.is_some() || chunks.is_empty() {
+                let pos = (x.unwrap_or(0.0) + glyph.dx, y.unwrap_or(cur_y) + glyph.dy);
+                cur_y = pos.1;
+                if x.is_some() && (y.is_some() || chunks.is_empty()) {
+                    line_start = pos;
+                }
+                chunks.push(Chunk {
+                    pos,
+                    transform: self.transform,
+                    pt_per_px: self.pt_per_px,
+                    runs: Vec::new(),
+                    length: None,
+                    width: None,
+                    end_x: pos.0,
+                });
+                ranges.push(None);
+            } else {
+                cur_y = y.unwrap_or(cur_y) + glyph.dy;
+            }
+            let chunk = chunks.last_mut().unwrap();
+            if glyph.collapsible && chunk.runs.is_empty() {
+                continue;
+            }
+            let range = ranges.last_mut().unwrap();
+            if !glyph.collapsible {
+                *range = Some((range.map_or(index, |r| r.0), index));
+            }
+            let baseline = cur_y - chunk.pos.1;
+            let space = if chunk.runs.is_empty() { 0.0 } else { glyph.dx };
+            let style = &self.styles[glyph.style];
+            match chunk.runs.last_mut() {
+                Some(run) if run.style == *style && run.baseline == baseline && space == 0.0 => {
+                    run.glyphs.push(glyph.clone());
+                }
+                _ => chunk.runs.push(Run {
+                    glyphs: vec![glyph.clone()],
+                    space,
+                    baseline,
+                    style: style.clone(),
+                    par_break: false,
+                }),
+            }
+        }
+        for chunk in &mut chunks {
+            while let Some(run) = chunk.runs.last_mut() {
+                if run.glyphs.last().is_some_and(|g| g.collapsible) {
+                    run.glyphs.pop();
+                }
+                if !run.glyphs.is_empty() {
+                    break;
+                }
+                chunk.runs.pop();
+            }
+            chunk.end_x = chunk.pos.0 + chunk.width();
+        }
+        for (chunk, range) in chunks.iter_mut().zip(ranges) {
+            let Some((first, last)) = range else {
+                continue;
+            };
+            // the innermost element that contains all of the chunk and nothing else
+            for (element, length, adjust) in &self.lengths {
+                let others = element
+                    .clone()
+                    .any(|i| (i < first || i > last) && !self.glyphs[i].collapsible);
+                if element.contains(&first) && element.contains(&last) && !others {
+                    chunk.length = Some((*length, *adjust));
+                    break;
+                }
+            }
+            if chunk.length.is_none()
+                && self
+                    .lengths
+                    .iter()
+                    .any(|(element, _, _)| element.contains(&first) || element.contains(&last))
+            {
+                warn!(
+                    "textLength over part of a text chunk is ignored, the text keeps its natural width"
+                );
+            }
+        }
+        chunks
+    }
+}
+
+impl Chunk {
+    fn is_blank(&self) -> bool {
+        self.runs
+            .iter()
+            .all(|run| run.glyphs.iter().all(|g| g.text.trim().is_empty()))
+    }
+
+    /// Font size of a run in svg units
+    fn em(&self, style: &Option<SvgStyle>) -> f64 {
+        style
+            .as_ref()
+            .and_then(|s| s.font_size)
+            .map_or(16.0, |size| size / self.pt_per_px)
+    }
+
+    /// Estimated advance of the content, without font metrics
+    fn width(&self) -> f64 {
+        self.runs
+            .iter()
+            .map(|run| {
+                let em = self.em(&run.style);
+                run.space
+                    + run
+                        .glyphs
+                        .iter()
+                        .map(|g| estimate_advance(&g.text) * em)
+                        .sum::<f64>()
+            })
+            .sum()
+    }
+
+    /// Appends `next` if it continues the line of this chunk, with a space in
+    /// between when there is a gap.
+    fn try_merge(&mut self, next: &Chunk, merge: TextMerge) -> bool {
+        if [self, next]
+            .iter()
+            .any(|c| c.length.is_some() || c.width.is_some())
+        {
+            return false;
+        }
+        if [self, next].iter().any(|c| c.is_vertical() || c.is_rtl()) {
+            return false;
+        }
+        let (t, n) = (&self.transform, &next.transform);
+        let eq = |a: f64, b: f64| (a - b).abs() <= 1e-9 * a.abs().max(b.abs()).max(1.0);
+        if !(eq(t.a, n.a) && eq(t.b, n.b) && eq(t.c, n.c) && eq(t.d, n.d)) {
+            return false;
+        }
+        // the position of the next chunk in this chunk's coordinate system
+        let det = t.a * t.d - t.b * t.c;
+        if det == 0.0 {
+            return false;
+        }
+        let (ex, ey) = (n.e - t.e, n.f - t.f);
+        let x = next.pos.0 + (t.d * ex - t.c * ey) / det;
+        let y = next.pos.1 + (t.a * ey - t.b * ex) / det;
+        let Some(last) = self.runs.last() else {
+            return false;
+        };
+        let em = self.em(&last.style);
+        let gap = (x - self.end_x) / em;
+        let max_gap = match merge {
+            TextMerge::None => return false,
+            TextMerge::Words => WORD_GAP,
+            TextMerge::Lines => 1.5,
+        };
+        if (y - self.pos.1).abs() > 0.2 * em || gap < -0.5 || gap > max_gap {
+            return false;
+        }
+        let ends_with_space = |chunk: &Chunk| {
+            chunk
+                .runs
+                .last()
+                .and_then(|r| r.glyphs.last())
+                .is_some_and(|g| g.text.trim().is_empty())
+        };
+        let mut runs = next.runs.iter();
+        if let Some(first) = runs.next() {
+            let mut first = first.clone();
+            let starts_with_space = first
+                .glyphs
+                .first()
+                .is_some_and(|g| g.text.trim().is_empty());
+            if gap > WORD_GAP && !ends_with_space(self) && !starts_with_space {
+                let mut space = first.glyphs[0].clone();
+                space.text = " ".to_string();
+                first.glyphs.insert(0, space);
+            }
+            let last = self.runs.last_mut().unwrap();
+            if last.style == first.style && last.baseline == first.baseline {
+                last.glyphs.extend(first.glyphs);
+            } else {
+                self.runs.push(first);
+            }
+        }
+        self.runs.extend(runs.cloned());
+        self.end_x = x + next.end_x - next.pos.0;
+        true
+    }
+
+    /// The style of the first run, whose writing mode and direction apply to the
+    /// whole chunk
+    fn base_style(&self) -> Option<&SvgStyle> {
+        self.runs.first().and_then(|run| run.style.as_ref())
+    }
+
+    fn is_vertical(&self) -> bool {
+        self.base_style().and_then(|s| s.writing_mode) == Some(WritingMode::Vertical)
+    }
+
+    fn is_rtl(&self) -> bool {
+        self.base_style().and_then(|s| s.rtl) == Some(true)
+    }
+
+    /// The runs laid out along a horizontal baseline
+    fn body(&self, options: &TextOptions) -> String {
+        let mut parts = Vec::new();
+        for run in &self.runs {
+            if run.par_break {
+                parts.push("parbreak()".to_string());
+            }
+            if run.space != 0.0 {
+                parts.push(format!("h({:.3}pt)", run.space * self.pt_per_px));
+            }
+            parts.push(format_run(
+                &run.glyphs,
+                &run.style,
+                run.baseline * self.pt_per_px,
+                options,
+            ));
+        }
+        if parts.len() == 1 {
+            parts.pop().unwrap()
+        } else {
+            format!("({})", parts.join(" + "))
+        }
+    }
+
+    /// The runs stacked top to bottom, characters that are not upright are turned
+    /// clockwise.
+    fn vertical_body(&self, options: &TextOptions) -> String {
+        let upright = |g: &Glyph| g.text.chars().any(is_upright);
+        if !self.runs.iter().any(|run| run.glyphs.iter().any(upright)) {
+            return format!("rotate(90deg, reflow: true, {})", self.body(options));
+        }
+        let mut items = Vec::new();
+        for run in &self.runs {
+            for group in run.glyphs.chunk_by(|a, b| !upright(a) && !upright(b)) {
+                let content = format_run(group, &run.style, 0.0, options);
+                if upright(&group[0]) {
+                    items.push(content);
+                } else {
+                    items.push(format!("rotate(90deg, reflow: true, {})", content));
+                }
+            }
+        }
+        // every character takes an em of height, like a vertical advance
+        format!(
+            "text(top-edge: 0.88em, bottom-edge: -0.12em, grid(align: center, {}))",
+            items.join(", ")
+        )
+    }
+
+    /// Emits the chunk as one `content`.
+    fn emit(&self, options: &TextOptions) {
+        // vertical text is positioned at the top of its central baseline, right to
+        // left text at its right end
+        let (anchor, mut body) = if let Some(width) = self.width {
+            let mut body = self.body(options);
+            match self.base_style().and_then(|s| s.text_align.as_deref()) {
+                Some("center") => body = format!("align(center, {})", body),
+                Some("right" | "end") => body = format!("align(right, {})", body),
+                Some("justify") => body = format!("{{ set par(justify: true); {} }}", body),
+                _ => {}
+            }
+            if self.is_rtl() {
+                body = format!("text(dir: rtl, {})", body);
+            }
+            (
+                "north-west",
+                format!("block(width: {:.3}pt, {})", width * self.pt_per_px, body),
+            )
+        } else if self.is_vertical() {
+            ("north", self.vertical_body(options))
+        } else if self.is_rtl() {
+            (
+                "south-east",
+                format!("text(dir: rtl, {})", self.body(options)),
+            )
+        } else {
+            ("south-west", self.body(options))
+        };
+        if let Some((length, adjust)) = self.length.filter(|_| !self.is_vertical()) {
+            // typst measures the natural width, svg distributes the difference
+            let length = length * self.pt_per_px;
+            let glyphs: usize = self.runs.iter().map(|run| run.glyphs.len()).sum();
+            body = match adjust {
+                LengthAdjust::Spacing if glyphs > 1 => format!(
+                    "context {{ let body = {}; text(tracking: ({:.3}pt - measure(body).width) / {}, body) }}",
+                    body,
+                    length,
+                    glyphs - 1
+                ),
+                LengthAdjust::Spacing => body,
+                LengthAdjust::SpacingAndGlyphs => format!(
+                    "context {{ let body = {}; scale(x: {:.3}pt / measure(body).width * 100%, origin: left, reflow: true, body) }}",
+                    body, length
+                ),
+            };
+        }
+        gen_content(
+            self.pos,
+            &self.transform,
+            anchor,
+            body,
+            options.backend.emitter(),
+        );
+    }
+}
+
+/// Gap between glyphs, in em, above which they are separate words
+const WORD_GAP: f64 = 0.15;
+
+/// Rough advance of a glyph in em, for detecting gaps between separately positioned
+/// glyphs.
+fn estimate_advance(text: &str) -> f64 {
+    match text.chars().next() {
+        Some(' ' | '\u{a0}') => 0.3,
+        Some('i' | 'j' | 'l' | 'I' | '.' | ',' | ':' | ';' | '!' | '\'' | '|') => 0.28,
+        Some('f' | 't' | 'r' | '(' | ')' | '[' | ']' | '-') => 0.38,
+        Some('m' | 'w') => 0.85,
+        Some('M' | 'W') => 0.95,
+        Some(c) if c >= '\u{2e80}' => 1.0,
+        Some(c) if c.is_uppercase() => 0.7,
+        _ => 0.52,
+    }
+}
+
+/// Writes laid out text. With merging, the last chunk is held back until it is
+/// clear that no following chunk continues it.
+#[derive(Debug, Default)]
+pub struct TextWriter {
+    pending: Option<Chunk>,
+}
+
+impl TextWriter {
+    /// Emits one `content` per text chunk, or per word or line when merging.
+    pub fn write(&mut self, layout: &TextLayout, options: &TextOptions) {
+        for chunk in layout.chunks() {
+            if chunk.is_blank() {
+                continue;
+            }
+            if options.merge == TextMerge::None {
+                chunk.emit(options);
+                continue;
+            }
+            let merged = match &mut self.pending {
+                Some(pending) => pending.try_merge(&chunk, options.merge),
+                None => false,
+            };
+            if !merged {
+                self.flush(options);
+                self.pending = Some(chunk);
+            }
+        }
+    }
+
+    /// Emits the held back chunk, before anything else is drawn over it.
+    pub fn flush(&mut self, options: &TextOptions) {
+        if let Some(chunk) = self.pending.take() {
+            chunk.emit(options);
+        }
+    }
+}
+
+/// Formats text as a typst content expression.
+pub fn format_text(text: &str, options: &TextOptions) -> String {
+    match options.mode {
+        TextMode::Markup if options.latex_math => {
+            let mut r = String::from("[");
+            for segment in split_math(text) {
+                match segment {
+                    Segment::Text(text) => r.push_str(&escape_markup(text)),
+                    Segment::Math { source, latex } => match to_typst_math(latex) {
+                        Ok(math) => r.push_str(&format!("${}$", math)),
+                        Err(e) => {
+                            debug!("Keeping math as text: {}", e);
+                            r.push_str(&escape_markup(source));
+                        }
+                    },
+                }
+            }
+            r.push(']');
+            r
+        }
+        TextMode::Markup => format!("[{}]", escape_markup(text)),
+        TextMode::Raw => string_literal(text),
+        TextMode::Passthrough => format!("[{}]", text),
+    }
+}
+
+/// Formats a run of glyphs as typst content, `baseline` shifts it down in points.
+fn format_run(
+    glyphs: &[Glyph],
+    style: &Option<SvgStyle>,
+    baseline: f64,
+    options: &TextOptions,
+) -> String {
+    let bidi_override = style.as_ref().and_then(|s| s.bidi_override) == Some(true);
+    let mut parts = Vec::new();
+    let mut plain = String::new();
+    for glyph in glyphs {
+        if (glyph.rotate == 0.0 && !bidi_override) || glyph.text.trim().is_empty() {
+            plain.push_str(&glyph.text);
+        } else {
+            if !plain.is_empty() {
+                parts.push(format_text(&plain, options));
+                plain.clear();
+            }
+            let mut content = format_text(&glyph.text, options);
+            if glyph.rotate != 0.0 {
+                // svg rotates each glyph around its origin, unlike a rotated text run
+                // the advances are unaffected, both rotate clockwise
+                content = format!(
+                    "rotate({:.3}deg, origin: left + bottom, {})",
+                    glyph.rotate, content
+                );
+            }
+            if bidi_override {
+                // boxes are neutral to the bidi algorithm, they follow the base
+                // direction in their logical order
+                content = format!("box({})", content);
+            }
+            parts.push(content);
+        }
+    }
+    if !plain.is_empty() || parts.is_empty() {
+        parts.push(format_text(&plain, options));
+    }
+    let mut body = if parts.len() == 1 {
+        parts.pop().unwrap()
+    } else {
+        format!("({})", parts.join(" + "))
+    };
+    let mut text = String::from("text(");
+    let mut shift_em = 0.0;
+    let mut baseline = baseline;
+    match style.as_ref().and_then(|s| s.baseline_shift) {
+        Some(BaselineShift::Sub) => body = format!("sub({})", body),
+        Some(BaselineShift::Super) => body = format!("super({})", body),
+        Some(BaselineShift::Em(em)) => shift_em = em,
+        Some(BaselineShift::Length(length)) => baseline -= length,
+        None => {}
+    }
+    // typst shifts the baseline down
+    match (baseline != 0.0, shift_em != 0.0) {
+        (true, true) => text.push_str(&format!(
+            "baseline: {:.3}pt + {:.3}em, ",
+            baseline, -shift_em
+        )),
+        (true, false) => text.push_str(&format!("baseline: {:.3}pt, ", baseline)),
+        (false, true) => text.push_str(&format!("baseline: {:.3}em, ", -shift_em)),
+        (false, false) => {}
+    }
+    if let Some(style) = style {
+        if let Some(spacing) = style.letter_spacing
+            && spacing != Spacing::Length(0.0)
+        {
+            text.push_str(&format!("tracking: {}, ", spacing));
+        }
+        if let Some(spacing) = style.word_spacing
+            && spacing != Spacing::Length(0.0)
+        {
+            text.push_str(&format!("spacing: 100% + {}, ", spacing));
+        }
+        if style.small_caps == Some(true) {
+            body = format!("smallcaps({})", body);
+        }
+        if let Some(decoration) = style.text_decoration {
+            if decoration.underline {
+                body = format!("underline({})", body);
+            }
+            if decoration.line_through {
+                body = format!("strike({})", body);
+            }
+            if decoration.overline {
+                body = format!("overline({})", body);
+            }
+        }
+        if let Some(font_size) = style.font_size {
+            text.push_str(&format!("size: {:.3}pt, ", font_size));
+        }
+        if let Some(font_family) = &style.font_family {
+            let fonts = options.font_map.resolve(font_family);
+            if !fonts.is_empty() {
+                text.push_str(&format!("font: {}, ", format_font_array(&fonts)));
+            }
+        }
+        if let Some(weight) = style.font_weight {
+            text.push_str(&format!("weight: {}, ", weight));
+        }
+        if let Some(font_style) = &style.font_style {
+            text.push_str(&format!("style: \"{}\", ", font_style));
+        }
+        if let Some(fill) = &style.fill
+            && fill != "none"
+        {
+            text.push_str(&format!("fill: {}, ", fill));
+        }
+    }
+    if text.len() > "text(".len() {
+        body = format!("{}{})", text, body);
+    }
+    if style.as_ref().and_then(|s| s.visible) == Some(false) {
+        body = format!("hide({})", body);
+    }
+    if let Some(link) = style.as_ref().and_then(|s| s.link.as_ref()) {
+        body = format!("link({}, {})", string_literal(link), body);
+    }
+    body
+}
+
+pub fn gen_content(
+    pos: (f64, f64),
+    transform: &Transform,
+    anchor: &str,
+    mut body: String,
+    emitter: &dyn Emitter,
+) {
+    let (x1, y1) = apply_transform(pos, transform);
+    let text_transform = TextTransform::from_transform(transform);
+    // the font size already accounts for scale_y, only the aspect ratio is left
+    let stretch = text_transform.scale_x / text_transform.font_scale();
+    if (stretch - 1.0).abs() > 1e-3 || text_transform.scale_y < 0.0 {
+        body = format!(
+            "scale(x: {:.3}%, y: {}%, reflow: true, {})",
+            stretch * 100.0,
+            if text_transform.scale_y < 0.0 {
+                -100
+            } else {
+                100
+            },
+            body
+        );
+    }
+    if text_transform.skew.abs() > 1e-3 {
+        body = format!(
+            "skew(ax: {:.3}deg, reflow: true, {})",
+            -text_transform.skew, body
+        );
+    }
+
+    emitter.content((x1, y1), anchor, text_transform.angle, &body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glyph(text: &str, rotate: f64) -> Glyph {
+        Glyph {
+            text: text.to_string(),
+            collapsible: false,
+            preserved: false,
+            x: None,
+            y: None,
+            dx: 0.0,
+            dy: 0.0,
+            rotate,
+            new_line: false,
+            style: 0,
+            path: None,
+        }
+    }
+
+    #[test]
+    fn glyphs_rotate_clockwise() {
+        let glyphs = [glyph("a", 30.0), glyph("b", 0.0)];
+        let run = format_run(&glyphs, &None, 0.0, &TextOptions::default());
+        assert!(run.contains("rotate(30.000deg, origin: left + bottom, [a])"));
+        assert!(!run.contains("-30.000deg"));
+    }
+}
@@ -0,0 +1,69 @@
+/// Escapes text so that typst markup shows it literally.
+pub fn escape_markup(text: &str) -> String {
+    let mut r = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    // a leading number followed by a dot is an enumeration marker
+    let mut leading_digits = true;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' | '#' | '[' | ']' | '$' | '*' | '_' | '`' | '<' | '>' | '@' | '=' | '-' | '+'
+            | '/' | '~' | '\'' | '"' => {
+                r.push('\\');
+                r.push(c);
+            }
+            // ellipsis shorthand and enumerations
+            '.' if chars.peek() == Some(&'.') || (leading_digits && !r.is_empty()) => {
+                r.push_str("\\.");
+            }
+            c => r.push(c),
+        }
+        leading_digits = leading_digits && c.is_ascii_digit();
+    }
+    r
+}
+
+/// Formats text as a typst string literal.
+pub fn string_literal(text: &str) -> String {
+    let mut r = String::with_capacity(text.len() + 2);
+    r.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => r.push_str("\\\""),
+            '\\' => r.push_str("\\\\"),
+            '\n' => r.push_str("\\n"),
+            '\r' => r.push_str("\\r"),
+            '\t' => r.push_str("\\t"),
+            c => r.push(c),
+        }
+    }
+    r.push('"');
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_syntax_is_escaped() {
+        assert_eq!(escape_markup("#x $y$"), r"\#x \$y\$");
+        assert_eq!(escape_markup("*bold* _em_"), r"\*bold\* \_em\_");
+        assert_eq!(escape_markup(r#"a\b "c""#), r#"a\\b \"c\""#);
+        assert_eq!(escape_markup("<label> @ref"), r"\<label\> \@ref");
+    }
+
+    #[test]
+    fn enumerations_and_ellipses_are_escaped() {
+        assert_eq!(escape_markup("1. item"), r"1\. item");
+        assert_eq!(escape_markup("wait..."), r"wait\.\..");
+        assert_eq!(escape_markup("3.5"), r"3\.5");
+    }
+
+    #[test]
+    fn string_literals() {
+        assert_eq!(string_literal("plain"), r#""plain""#);
+        assert_eq!(string_literal(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(string_literal("#$*_"), r##""#$*_""##);
+        assert_eq!(string_literal("line\nbreak"), r#""line\nbreak""#);
+    }
+}